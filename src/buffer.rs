@@ -1,7 +1,12 @@
+// undo で戻れる回数の上限
+const HISTORY_LIMIT: usize = 1000;
+
 pub struct ViewBuffer {
     pub cursor: usize,
     pub buffer: String,
     pub filename: String,
+    pub mark: Option<usize>,
+    history: Vec<(String, usize)>,
 }
 
 impl ViewBuffer {
@@ -10,6 +15,8 @@ impl ViewBuffer {
             cursor: 0,
             buffer: String::new(),
             filename: filename.into(),
+            mark: None,
+            history: Vec::new(),
         }
     }
 
@@ -29,6 +36,7 @@ impl ViewBuffer {
             .rev()
             .find(|(i, _)| *i < self.cursor)
         {
+            self.snapshot();
             self.buffer.remove(prev_idx);
             self.cursor = prev_idx;
         }
@@ -56,4 +64,799 @@ impl ViewBuffer {
             self.cursor = self.buffer.len();
         }
     }
+
+    // 現在の状態を履歴に積む (undo で戻せるようにする)
+    pub fn snapshot(&mut self) {
+        if self.history.len() >= HISTORY_LIMIT {
+            self.history.remove(0);
+        }
+        self.history.push((self.buffer.clone(), self.cursor));
+    }
+
+    // 直前の snapshot の状態に戻す
+    pub fn undo(&mut self) {
+        if let Some((buffer, cursor)) = self.history.pop() {
+            self.buffer = buffer;
+            self.cursor = cursor;
+            self.mark = None;
+        }
+    }
+
+    // 指定位置を含む行の先頭
+    pub fn line_start(&self, pos: usize) -> usize {
+        self.buffer[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0)
+    }
+
+    // 指定位置を含む行の末尾 (改行文字の手前)
+    pub fn line_end(&self, pos: usize) -> usize {
+        self.buffer[pos..].find('\n').map(|i| pos + i).unwrap_or(self.buffer.len())
+    }
+
+    // 1 始まりの行番号から行頭の位置を求める
+    pub fn line_offset(&self, line: usize) -> Option<usize> {
+        if line == 0 {
+            return None;
+        }
+        if line == 1 {
+            return Some(0);
+        }
+        self.buffer
+            .match_indices('\n')
+            .nth(line - 2)
+            .map(|(i, _)| i + 1)
+    }
+
+    // マークとカーソルで挟まれた範囲
+    pub fn region(&self) -> Option<(usize, usize)> {
+        self.mark.map(|mark| (mark.min(self.cursor), mark.max(self.cursor)))
+    }
+
+    // 範囲が含む行全体。マークがなければ None
+    pub fn region_lines(&self) -> Option<(usize, usize)> {
+        self.region().map(|(start, end)| (self.line_start(start), self.line_end(end)))
+    }
+
+    // 行単位の操作の対象範囲。マークがなければバッファー全体 (末尾の改行は含めない)
+    pub fn lines_range(&self) -> (usize, usize) {
+        self.region_lines().unwrap_or_else(|| {
+            let end = self.buffer.strip_suffix('\n').map(|s| s.len()).unwrap_or(self.buffer.len());
+            (0, end)
+        })
+    }
+
+    // 範囲が含む行全体。マークがなければカーソルのある行
+    pub fn current_lines_range(&self) -> (usize, usize) {
+        self.region_lines().unwrap_or((self.line_start(self.cursor), self.line_end(self.cursor)))
+    }
+
+    // 範囲を置き換える。カーソルとマークは置き換え後の位置にずらす
+    pub fn replace_range(&mut self, start: usize, end: usize, text: &str) {
+        self.snapshot();
+        self.buffer.replace_range(start..end, text);
+
+        let shift = |pos: usize| -> usize {
+            if pos >= end {
+                pos + text.len() - (end - start)
+            } else if pos > start {
+                start + (pos - start).min(text.len())
+            } else {
+                pos
+            }
+        };
+        self.cursor = self.clamp(shift(self.cursor));
+        self.mark = self.mark.map(|mark| self.clamp(shift(mark)));
+    }
+
+    // 位置を文字境界に収める
+    pub fn clamp(&self, pos: usize) -> usize {
+        let mut pos = pos.min(self.buffer.len());
+        while !self.buffer.is_char_boundary(pos) {
+            pos -= 1;
+        }
+        pos
+    }
+
+    // 対象範囲の行を f で変換する。マークがあれば変換後の行を選択したままにする
+    pub fn edit_lines<F: FnOnce(Vec<&str>) -> Vec<String>>(&mut self, (start, end): (usize, usize), f: F) {
+        let lines: Vec<&str> = self.buffer[start..end].split('\n').collect();
+        let text = f(lines).join("\n");
+        self.replace_range(start, end, &text);
+
+        if self.mark.is_some() {
+            self.mark = Some(start);
+            self.cursor = start + text.len();
+        }
+    }
+
+    // カーソル位置の文字の範囲
+    pub fn char_range(&self) -> (usize, usize) {
+        let end = self.char_at(self.cursor).map(|c| self.cursor + c.len_utf8()).unwrap_or(self.cursor);
+        (self.cursor, end)
+    }
+
+    // カーソル位置の単語の範囲。単語の上になければ空の範囲
+    pub fn word_range(&self, word_chars: &str) -> (usize, usize) {
+        let class = match (self.class_at(self.cursor, word_chars), self.class_before(self.cursor, word_chars)) {
+            (Some(class), _) if class != CharClass::Space => class,
+            (_, Some(class)) if class != CharClass::Space => class,
+            _ => return (self.cursor, self.cursor),
+        };
+
+        let mut start = self.cursor;
+        while self.class_before(start, word_chars) == Some(class) {
+            start = self.prev_pos(start);
+        }
+        let mut end = self.cursor;
+        while self.class_at(end, word_chars) == Some(class) {
+            end = self.next_pos(end);
+        }
+        (start, end)
+    }
+
+    // pos が文字の境界でなければ None
+    fn char_at(&self, pos: usize) -> Option<char> {
+        self.buffer.get(pos..).and_then(|rest| rest.chars().next())
+    }
+
+    fn char_before(&self, pos: usize) -> Option<char> {
+        self.buffer.get(..pos).and_then(|before| before.chars().next_back())
+    }
+
+    fn prev_pos(&self, pos: usize) -> usize {
+        self.char_before(pos).map(|c| pos - c.len_utf8()).unwrap_or(0)
+    }
+
+    fn next_pos(&self, pos: usize) -> usize {
+        self.char_at(pos).map(|c| pos + c.len_utf8()).unwrap_or(pos)
+    }
+
+    fn class_at(&self, pos: usize, word_chars: &str) -> Option<CharClass> {
+        self.char_at(pos).map(|c| char_class(c, word_chars))
+    }
+
+    fn class_before(&self, pos: usize, word_chars: &str) -> Option<CharClass> {
+        self.char_before(pos).map(|c| char_class(c, word_chars))
+    }
+
+    // 次の単語の先頭
+    pub fn word_forward(&self, word_chars: &str) -> usize {
+        let mut pos = self.cursor;
+        if let Some(class) = self.class_at(pos, word_chars).filter(|c| *c != CharClass::Space) {
+            while self.class_at(pos, word_chars) == Some(class) {
+                pos = self.next_pos(pos);
+            }
+        }
+        while self.class_at(pos, word_chars) == Some(CharClass::Space) {
+            pos = self.next_pos(pos);
+        }
+        pos
+    }
+
+    // 現在または前の単語の先頭
+    pub fn word_backward(&self, word_chars: &str) -> usize {
+        let mut pos = self.cursor;
+        while self.class_before(pos, word_chars) == Some(CharClass::Space) {
+            pos = self.prev_pos(pos);
+        }
+        if let Some(class) = self.class_before(pos, word_chars) {
+            while self.class_before(pos, word_chars) == Some(class) {
+                pos = self.prev_pos(pos);
+            }
+        }
+        pos
+    }
+
+    // 現在または次の単語の末尾 (最後の文字の直後)
+    pub fn word_end_forward(&self, word_chars: &str) -> usize {
+        let mut pos = self.cursor;
+        while self.class_at(pos, word_chars) == Some(CharClass::Space) {
+            pos = self.next_pos(pos);
+        }
+        if let Some(class) = self.class_at(pos, word_chars) {
+            while self.class_at(pos, word_chars) == Some(class) {
+                pos = self.next_pos(pos);
+            }
+        }
+        pos
+    }
+
+    // 前の単語の末尾
+    pub fn word_end_backward(&self, word_chars: &str) -> usize {
+        let mut pos = self.cursor;
+        if let Some(class) = self.class_before(pos, word_chars).filter(|c| *c != CharClass::Space) {
+            while self.class_before(pos, word_chars) == Some(class) {
+                pos = self.prev_pos(pos);
+            }
+        }
+        while self.class_before(pos, word_chars) == Some(CharClass::Space) {
+            pos = self.prev_pos(pos);
+        }
+        pos
+    }
+
+    // 段落の先頭 (空行の次の空行でない行の先頭) の一覧
+    fn paragraph_starts(&self) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut pos = 0;
+        let mut prev_blank = true;
+        for line in self.buffer.split('\n') {
+            let blank = line.trim().is_empty();
+            if !blank && prev_blank {
+                starts.push(pos);
+            }
+            prev_blank = blank;
+            pos += line.len() + 1;
+        }
+        starts
+    }
+
+    // 文の先頭の一覧。段落の先頭と、文末記号と空白の後が文の先頭になる
+    fn sentence_starts(&self) -> Vec<usize> {
+        let mut starts = self.paragraph_starts();
+        let chars: Vec<(usize, char)> = self.buffer.char_indices().collect();
+
+        for (i, (_, c)) in chars.iter().enumerate() {
+            let cjk = matches!(c, '。' | '！' | '？');
+            if !cjk && !matches!(c, '.' | '!' | '?') {
+                continue;
+            }
+
+            let mut j = i + 1;
+            while j < chars.len() && matches!(chars[j].1, '"' | '\'' | ')' | ']' | '」' | '』' | '）') {
+                j += 1;
+            }
+            let mut k = j;
+            while k < chars.len() && chars[k].1.is_whitespace() {
+                k += 1;
+            }
+            if k < chars.len() && (cjk || k > j) {
+                starts.push(chars[k].0);
+            }
+        }
+
+        starts.sort();
+        starts.dedup();
+        starts
+    }
+
+    pub fn sentence_forward(&self) -> usize {
+        self.sentence_starts().into_iter().find(|pos| *pos > self.cursor).unwrap_or(self.buffer.len())
+    }
+
+    pub fn sentence_backward(&self) -> usize {
+        self.sentence_starts().into_iter().rev().find(|pos| *pos < self.cursor).unwrap_or(0)
+    }
+
+    pub fn paragraph_forward(&self) -> usize {
+        self.paragraph_starts().into_iter().find(|pos| *pos > self.cursor).unwrap_or(self.buffer.len())
+    }
+
+    pub fn paragraph_backward(&self) -> usize {
+        self.paragraph_starts().into_iter().rev().find(|pos| *pos < self.cursor).unwrap_or(0)
+    }
+
+    // 範囲の大文字・小文字を変換する
+    pub fn convert_case(&mut self, start: usize, end: usize, case: Case) {
+        let text = &self.buffer[start..end];
+        let converted = match case {
+            Case::Upper => text.to_uppercase(),
+            Case::Lower => text.to_lowercase(),
+            Case::Title => title_case(text),
+            Case::Swap => swap_case(text),
+        };
+        if converted != text {
+            self.replace_range(start, end, &converted);
+        }
+    }
+
+    pub fn sort_lines(&mut self, options: &str) {
+        let numeric = options.contains('n');
+        let ignore_case = options.contains('i');
+        let reverse = options.contains('r');
+        let field: usize = options.chars().filter(|c| c.is_ascii_digit()).collect::<String>().parse().unwrap_or(0);
+
+        self.edit_lines(self.lines_range(), |lines| {
+            let key = |line: &str| -> String {
+                let key = if field > 0 {
+                    line.split_whitespace().nth(field - 1).unwrap_or("")
+                } else {
+                    line
+                };
+                if ignore_case { key.to_lowercase() } else { key.to_string() }
+            };
+
+            let mut lines: Vec<String> = lines.into_iter().map(String::from).collect();
+            if numeric {
+                lines.sort_by(|a, b| number_prefix(&key(a)).total_cmp(&number_prefix(&key(b))));
+            } else {
+                lines.sort_by_key(|line| key(line));
+            }
+            if reverse {
+                lines.reverse();
+            }
+            lines
+        });
+    }
+
+    // 隣り合う重複行を取り除く
+    pub fn uniq_lines(&mut self) {
+        self.edit_lines(self.lines_range(), |lines| {
+            let mut lines: Vec<String> = lines.into_iter().map(String::from).collect();
+            lines.dedup();
+            lines
+        });
+    }
+
+    pub fn reverse_lines(&mut self) {
+        self.edit_lines(self.lines_range(), |lines| lines.into_iter().rev().map(String::from).collect());
+    }
+
+    // シード付きで行をシャッフルする (同じシードなら同じ結果になる)
+    pub fn shuffle_lines(&mut self, seed: u64) {
+        self.edit_lines(self.lines_range(), |lines| {
+            let mut lines: Vec<String> = lines.into_iter().map(String::from).collect();
+            let mut rng = Rng::new(seed);
+            for i in (1..lines.len()).rev() {
+                let j = (rng.next() % (i as u64 + 1)) as usize;
+                lines.swap(i, j);
+            }
+            lines
+        });
+    }
+
+    // 行を字下げする (空行はそのまま)
+    pub fn indent_lines(&mut self, indent: &str) {
+        self.edit_lines(self.current_lines_range(), |lines| {
+            lines.into_iter().map(|line| if line.is_empty() { String::new() } else { format!("{}{}", indent, line) }).collect()
+        });
+    }
+
+    // 行頭の空白を width 桁ぶん取り除く
+    pub fn dedent_lines(&mut self, width: usize, tab_width: usize) {
+        self.edit_lines(self.current_lines_range(), |lines| {
+            lines.into_iter().map(|line| {
+                let mut column = 0;
+                let mut cut = 0;
+                for c in line.chars() {
+                    match c {
+                        ' ' => column += 1,
+                        '\t' => column = (column / tab_width + 1) * tab_width,
+                        _ => break,
+                    }
+                    if column > width {
+                        // 幅を越えるタブも 1 段として取り除く
+                        if c == '\t' {
+                            cut += 1;
+                        }
+                        break;
+                    }
+                    cut += 1;
+                    if column == width {
+                        break;
+                    }
+                }
+                line[cut..].to_string()
+            }).collect()
+        });
+    }
+
+    // タブを空白に展開する
+    pub fn expand_tabs(&mut self, tab_width: usize) {
+        self.edit_lines(self.lines_range(), |lines| {
+            lines.into_iter().map(|line| {
+                let mut result = String::new();
+                let mut column = 0;
+                for c in line.chars() {
+                    if c == '\t' {
+                        let next = (column / tab_width + 1) * tab_width;
+                        result.push_str(&" ".repeat(next - column));
+                        column = next;
+                    } else {
+                        result.push(c);
+                        column += 1;
+                    }
+                }
+                result
+            }).collect()
+        });
+    }
+
+    // 行頭の空白をできるだけタブにする
+    pub fn unexpand_tabs(&mut self, tab_width: usize) {
+        self.edit_lines(self.lines_range(), |lines| {
+            lines.into_iter().map(|line| {
+                let body = line.trim_start_matches([' ', '\t']);
+                let mut column = 0;
+                for c in line[..line.len() - body.len()].chars() {
+                    if c == '\t' {
+                        column = (column / tab_width + 1) * tab_width;
+                    } else {
+                        column += 1;
+                    }
+                }
+                format!("{}{}{}", "\t".repeat(column / tab_width), " ".repeat(column % tab_width), body)
+            }).collect()
+        });
+    }
+
+    // 行末の空白を取り除く
+    pub fn strip_trailing_whitespace(&mut self) {
+        self.edit_lines(self.lines_range(), |lines| lines.into_iter().map(|line| line.trim_end().to_string()).collect());
+    }
+
+    // 連続する空行を 1 行にまとめる
+    pub fn collapse_blank_lines(&mut self) {
+        self.edit_lines(self.lines_range(), |lines| {
+            let mut result: Vec<String> = Vec::new();
+            for line in lines {
+                let blank = line.trim().is_empty();
+                if blank && result.last().is_some_and(|last| last.is_empty()) {
+                    continue;
+                }
+                result.push(if blank { String::new() } else { line.to_string() });
+            }
+            result
+        });
+    }
+
+    // カーソルのある段落 (空行で区切られた行の並び) の範囲。空行の上なら None
+    pub fn paragraph_range(&self) -> Option<(usize, usize)> {
+        let blank = |start: usize, end: usize| self.buffer[start..end].trim().is_empty();
+
+        let mut start = self.line_start(self.cursor);
+        let mut end = self.line_end(self.cursor);
+        if blank(start, end) {
+            return None;
+        }
+        while start > 0 {
+            let prev = self.line_start(start - 1);
+            if blank(prev, start - 1) {
+                break;
+            }
+            start = prev;
+        }
+        while end < self.buffer.len() {
+            let next = self.line_end(end + 1);
+            if blank(end + 1, next) {
+                break;
+            }
+            end = next;
+        }
+        Some((start, end))
+    }
+
+    // 範囲の行を width 桁に収まるように詰め直す
+    pub fn fill_lines(&mut self, range: (usize, usize), width: usize) {
+        self.edit_lines(range, |lines| fill(&lines, width));
+    }
+
+    // ファイル末尾の改行をちょうど 1 つにする
+    pub fn ensure_final_newline(&mut self) {
+        let trimmed = self.buffer.trim_end_matches('\n').len();
+        if !self.buffer.is_empty() && self.buffer[trimmed..] != *"\n" {
+            let len = self.buffer.len();
+            self.replace_range(trimmed, len, "\n");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Case {
+    Upper,
+    Lower,
+    Title,
+    Swap,
+}
+
+// 単語の区切りを決めるための文字の種類。同じ種類の文字の並びを 1 つの単語とみなす
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CharClass {
+    Space,
+    Word,
+    Punct,
+    Kanji,
+    Hiragana,
+    Katakana,
+    Hangul,
+}
+
+// word_chars に含まれる文字は英数字と同じく単語の一部として扱う
+pub fn char_class(c: char, word_chars: &str) -> CharClass {
+    match c {
+        _ if c.is_whitespace() => CharClass::Space,
+        '\u{3005}' | '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' | '\u{20000}'..='\u{2FFFF}' => CharClass::Kanji,
+        '\u{3040}'..='\u{309F}' => CharClass::Hiragana,
+        '\u{30A0}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' | '\u{FF66}'..='\u{FF9F}' => CharClass::Katakana,
+        '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' | '\u{AC00}'..='\u{D7AF}' => CharClass::Hangul,
+        _ if c.is_alphanumeric() || word_chars.contains(c) => CharClass::Word,
+        _ => CharClass::Punct,
+    }
+}
+
+// 単語の先頭を大文字に、残りを小文字にする
+fn title_case(text: &str) -> String {
+    let mut result = String::new();
+    let mut in_word = false;
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            if in_word {
+                result.extend(c.to_lowercase());
+            } else if let Some(title) = title_char(c) {
+                result.push(title);
+            } else {
+                result.extend(c.to_uppercase());
+            }
+            in_word = true;
+        } else {
+            result.push(c);
+            in_word = in_word && c == '\'';
+        }
+    }
+    result
+}
+
+// 大文字と異なるタイトルケースを持つ文字 (ǅ, ǈ, ǋ, ǲ)
+fn title_char(c: char) -> Option<char> {
+    match c {
+        '\u{01C4}'..='\u{01C6}' => Some('\u{01C5}'),
+        '\u{01C7}'..='\u{01C9}' => Some('\u{01C8}'),
+        '\u{01CA}'..='\u{01CC}' => Some('\u{01CB}'),
+        '\u{01F1}'..='\u{01F3}' => Some('\u{01F2}'),
+        _ => None,
+    }
+}
+
+fn swap_case(text: &str) -> String {
+    let mut result = String::new();
+    for c in text.chars() {
+        if c.is_lowercase() {
+            result.extend(c.to_uppercase());
+        } else if c.is_uppercase() {
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+// 表示幅。全角の文字は 2 桁とみなす
+pub fn char_width(c: char) -> usize {
+    match c {
+        '\u{1100}'..='\u{115F}'
+        | '\u{2E80}'..='\u{303E}'
+        | '\u{3041}'..='\u{A4CF}'
+        | '\u{AC00}'..='\u{D7A3}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FE30}'..='\u{FE4F}'
+        | '\u{FF00}'..='\u{FF60}'
+        | '\u{FFE0}'..='\u{FFE6}'
+        | '\u{20000}'..='\u{3FFFD}' => 2,
+        _ => 1,
+    }
+}
+
+fn str_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+// 行頭に来てはいけない文字 (前の文字とつなげて折り返す)
+fn is_no_break_before(c: char) -> bool {
+    matches!(c, '、' | '。' | '，' | '．' | '」' | '』' | '）' | '】' | '！' | '？' | 'ー' | 'っ' | 'ゃ' | 'ゅ' | 'ょ' | 'ッ' | 'ャ' | 'ュ' | 'ョ')
+}
+
+// 行頭の字下げとコメント記号 (例: "    // ") を取り出す
+// "*" や "#" は箇条書きや見出しと区別できないので含めない
+fn line_prefix(line: &str) -> &str {
+    const MARKERS: [&str; 8] = ["//!", "///", "//", "--", ";;", ";", ">", "%"];
+
+    let indent = line.len() - line.trim_start().len();
+    let rest = &line[indent..];
+    let marker = MARKERS.iter().find(|m| rest.starts_with(**m)).map(|m| m.len()).unwrap_or(0);
+    let spaces = rest[marker..].len() - rest[marker..].trim_start().len();
+    if marker == 0 {
+        &line[..indent]
+    } else {
+        &line[..indent + marker + spaces]
+    }
+}
+
+// 段落を詰め直す。1 行目の字下げとコメント記号を各行の先頭に付ける
+// 全角の文字どうしの間では空白なしで折り返せる
+fn fill(lines: &[&str], width: usize) -> Vec<String> {
+    let prefix = line_prefix(lines.first().copied().unwrap_or(""));
+    let marker = prefix.trim();
+
+    // (文字列, 前に空白を入れるか)
+    let mut atoms: Vec<(String, bool)> = Vec::new();
+    for line in lines {
+        let body = line.trim_start();
+        let body = body.strip_prefix(marker).unwrap_or(body).trim();
+        let mut first_in_line = true;
+
+        for word in body.split_whitespace() {
+            let mut space = true;
+            if first_in_line {
+                // 改行をはさんで全角の文字が続くときは空白を入れない
+                let prev_wide = atoms.last().and_then(|(text, _)| text.chars().last()).is_some_and(|c| char_width(c) == 2);
+                let next_wide = word.chars().next().is_some_and(|c| char_width(c) == 2);
+                space = !(prev_wide && next_wide);
+            }
+            first_in_line = false;
+
+            let mut piece = String::new();
+            let mut prev_wide = false;
+            for c in word.chars() {
+                let wide = char_width(c) == 2;
+                if !piece.is_empty() && (wide || prev_wide) && !is_no_break_before(c) {
+                    atoms.push((std::mem::take(&mut piece), space));
+                    space = false;
+                }
+                piece.push(c);
+                prev_wide = wide;
+            }
+            if !piece.is_empty() {
+                if is_no_break_before(piece.chars().next().unwrap_or(' ')) && !space {
+                    if let Some((last, _)) = atoms.last_mut() {
+                        last.push_str(&piece);
+                        continue;
+                    }
+                }
+                atoms.push((piece, space));
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut line = String::new();
+    let mut line_width = str_width(prefix);
+    for (text, space) in atoms {
+        let text_width = str_width(&text) + if space && !line.is_empty() { 1 } else { 0 };
+        if !line.is_empty() && line_width + text_width > width {
+            result.push(format!("{}{}", prefix, line));
+            line.clear();
+            line_width = str_width(prefix);
+        }
+        if space && !line.is_empty() {
+            line.push(' ');
+            line_width += 1;
+        }
+        line_width += str_width(&text);
+        line.push_str(&text);
+    }
+    if !line.is_empty() || result.is_empty() {
+        result.push(format!("{}{}", prefix, line));
+    }
+    result
+}
+
+// 行頭の数値部分を読む。数値でなければ 0
+fn number_prefix(s: &str) -> f64 {
+    let s = s.trim_start();
+    let mut end = 0;
+    for (i, c) in s.char_indices() {
+        if c.is_ascii_digit() || c == '.' || (i == 0 && (c == '-' || c == '+')) {
+            end = i + c.len_utf8();
+        } else {
+            break;
+        }
+    }
+    s[..end].parse().unwrap_or(0.0)
+}
+
+// 依存を増やさないための簡単な疑似乱数 (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // splitmix64 でシードを散らす (0 でも動くように)
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Self((z ^ (z >> 31)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str) -> ViewBuffer {
+        let mut buffer = ViewBuffer::new("test.txt");
+        buffer.buffer = text.to_string();
+        buffer
+    }
+
+    // バッファー全体を選択した状態
+    fn selected(text: &str) -> ViewBuffer {
+        let mut buffer = buffer(text);
+        buffer.mark = Some(0);
+        buffer.cursor = text.len();
+        buffer
+    }
+
+    fn sorted(text: &str, options: &str) -> String {
+        let mut buffer = buffer(text);
+        buffer.sort_lines(options);
+        buffer.buffer
+    }
+
+    #[test]
+    fn sort() {
+        assert_eq!(sorted("b\nC\na\n", ""), "C\na\nb\n");
+        assert_eq!(sorted("b\nC\na\n", "i"), "a\nb\nC\n");
+        assert_eq!(sorted("10\n9\n-1.5\n", "n"), "-1.5\n9\n10\n");
+        assert_eq!(sorted("10\n9\n-1.5\n", "nr"), "10\n9\n-1.5\n");
+        assert_eq!(sorted("x 3\ny 1\nz 2", "n2"), "y 1\nz 2\nx 3");
+    }
+
+    #[test]
+    fn uniq_and_shuffle() {
+        let mut uniq = buffer("a\na\nb\na\n");
+        uniq.uniq_lines();
+        assert_eq!(uniq.buffer, "a\nb\na\n");
+
+        let text = "1\n2\n3\n4\n5\n6\n7\n8";
+        let mut first = buffer(text);
+        let mut second = buffer(text);
+        first.shuffle_lines(42);
+        second.shuffle_lines(42);
+        assert_eq!(first.buffer, second.buffer);
+
+        let mut lines: Vec<&str> = first.buffer.split('\n').collect();
+        lines.sort();
+        assert_eq!(lines.join("\n"), text);
+    }
+
+    #[test]
+    fn indentation() {
+        let mut dedent = selected("\t\tx\n    y\n  z");
+        dedent.dedent_lines(4, 4);
+        assert_eq!(dedent.buffer, "\tx\ny\nz");
+
+        let mut dedent = selected("  \tx");
+        dedent.dedent_lines(2, 4);
+        assert_eq!(dedent.buffer, "\tx");
+
+        let mut unexpand = buffer("        x\n      y\n \tz");
+        unexpand.unexpand_tabs(4);
+        assert_eq!(unexpand.buffer, "\t\tx\n\t  y\n\tz");
+    }
+
+    #[test]
+    fn blank_lines() {
+        let mut collapse = buffer("a\n\n  \n\nb\n");
+        collapse.collapse_blank_lines();
+        assert_eq!(collapse.buffer, "a\n\nb\n");
+
+        let mut newline = buffer("a\n\n\n");
+        newline.ensure_final_newline();
+        assert_eq!(newline.buffer, "a\n");
+
+        let mut newline = buffer("a");
+        newline.ensure_final_newline();
+        assert_eq!(newline.buffer, "a\n");
+
+        let mut empty = buffer("");
+        empty.ensure_final_newline();
+        assert_eq!(empty.buffer, "");
+    }
+
+    #[test]
+    fn fill_paragraph() {
+        assert_eq!(fill(&["one two three", "four five"], 10), vec!["one two", "three four", "five"]);
+        assert_eq!(fill(&["    // one two", "    // three four"], 14), vec!["    // one two", "    // three", "    // four"]);
+        assert_eq!(fill(&["* one two three"], 10), vec!["* one two", "three"]);
+
+        // 全角の文字は 2 桁で数え、句読点は行頭に来ない
+        assert_eq!(fill(&["日本語の文章を", "折り返す。"], 10), vec!["日本語の文", "章を折り返", "す。"]);
+        assert_eq!(fill(&["あいうえ。"], 8), vec!["あいう", "え。"]);
+    }
 }
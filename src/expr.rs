@@ -0,0 +1,977 @@
+// 中置記法の数式
+//
+// (...) の中身を構文木にして評価する。演算子には優先順位と結合性があり、
+// 変数は名前だけ (a + 1) でも $a$ でも参照できる。関数呼び出しは name(引数, ...) の形。
+// 以前からの前置記法 ((+*3;*5;) など) は pleco.rs の process_expr がそのまま扱う。
+
+use crate::lexer::{InterpPart, Token};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Pow,
+}
+
+impl BinOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Gt => ">",
+            BinOp::Le => "<=",
+            BinOp::Ge => ">=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::Pow => "**",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Value(Token),
+    Interp(Vec<InterpPart>),
+    Var(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    Index(Box<Expr>, Box<Expr>),
+}
+
+// 数式の評価に必要な外側の情報 (変数やバッファーを読む関数)
+pub trait Env {
+    fn get_var(&self, name: &str) -> Result<Token, String>;
+    fn call(&self, name: &str, args: Vec<Token>) -> Result<Token, String>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Interp(Vec<InterpPart>),
+    Ident(String),
+    Var(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Colon,
+    Comma,
+}
+
+// 長いものから順に並べる (<= を < より先に見る)
+const OPERATORS: [&str; 21] = [
+    "**", "<<", ">>", "&&", "||", "==", "!=", "<=", ">=",
+    "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!", "~",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Tok>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    'outer: while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_ascii_digit() {
+            let start = i;
+            let digits = |i: &mut usize| {
+                while *i < chars.len() && chars[*i].is_ascii_digit() {
+                    *i += 1;
+                }
+            };
+            digits(&mut i);
+
+            // 小数点か指数があれば浮動小数点数 (1.5, 2e3, 1.5e-3)
+            let mut float = false;
+            if i + 1 < chars.len() && chars[i] == '.' && chars[i + 1].is_ascii_digit() {
+                float = true;
+                i += 1;
+                digits(&mut i);
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let sign = if i + 1 < chars.len() && (chars[i + 1] == '+' || chars[i + 1] == '-') { 1 } else { 0 };
+                if i + 1 + sign < chars.len() && chars[i + 1 + sign].is_ascii_digit() {
+                    float = true;
+                    i += 1 + sign;
+                    digits(&mut i);
+                }
+            }
+
+            let text: String = chars[start..i].iter().collect();
+            if float {
+                tokens.push(Tok::Float(text.parse().map_err(|_| format!("invalid number: {}", text))?));
+            } else {
+                tokens.push(Tok::Integer(text.parse().map_err(|_| format!("number out of range: {}", text))?));
+            }
+            continue;
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Tok::Ident(chars[start..i].iter().collect()));
+            continue;
+        }
+
+        match c {
+            '"' => {
+                let mut string = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' && i + 1 < chars.len() {
+                        i += 1;
+                        string.push(match chars[i] {
+                            'n' => '\n',
+                            't' => '\t',
+                            other => other,
+                        });
+                    } else {
+                        string.push(chars[i]);
+                    }
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(String::from("unterminated string in expression"));
+                }
+                i += 1;
+                tokens.push(Tok::String(string));
+                continue;
+            }
+            '`' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && chars[i] != '`' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                if i >= chars.len() {
+                    return Err(String::from("unterminated string in expression"));
+                }
+                i += 1;
+                let src: String = chars[start..i].iter().collect();
+                match crate::lexer::Lexer::new(&src).next_token() {
+                    Some(Token::Interp(parts)) => tokens.push(Tok::Interp(parts)),
+                    _ => return Err(String::from("invalid interpolated string")),
+                }
+                continue;
+            }
+            '$' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i] != '$' {
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(String::from("unterminated variable in expression"));
+                }
+                tokens.push(Tok::Var(chars[start..i].iter().collect()));
+                i += 1;
+                continue;
+            }
+            '(' => {
+                tokens.push(Tok::LParen);
+                i += 1;
+                continue;
+            }
+            ')' => {
+                tokens.push(Tok::RParen);
+                i += 1;
+                continue;
+            }
+            '[' => {
+                tokens.push(Tok::LBracket);
+                i += 1;
+                continue;
+            }
+            ']' => {
+                tokens.push(Tok::RBracket);
+                i += 1;
+                continue;
+            }
+            '{' => {
+                tokens.push(Tok::LBrace);
+                i += 1;
+                continue;
+            }
+            '}' => {
+                tokens.push(Tok::RBrace);
+                i += 1;
+                continue;
+            }
+            ':' => {
+                tokens.push(Tok::Colon);
+                i += 1;
+                continue;
+            }
+            ',' => {
+                tokens.push(Tok::Comma);
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        for op in OPERATORS {
+            let len = op.chars().count();
+            if chars[i..].iter().take(len).copied().eq(op.chars()) {
+                tokens.push(Tok::Op(op));
+                i += len;
+                continue 'outer;
+            }
+        }
+
+        return Err(format!("unexpected character in expression: '{}'", c));
+    }
+
+    Ok(tokens)
+}
+
+// 二項演算子の (優先順位, 演算子)。数字が大きいほど強く結びつく
+// ** は単項演算子より強く、右結合なので parse_power で別に扱う
+fn binary_op(op: &str) -> Option<(u8, BinOp)> {
+    match op {
+        "||" => Some((1, BinOp::Or)),
+        "&&" => Some((2, BinOp::And)),
+        "|" => Some((3, BinOp::BitOr)),
+        "^" => Some((4, BinOp::BitXor)),
+        "&" => Some((5, BinOp::BitAnd)),
+        "==" => Some((6, BinOp::Eq)),
+        "!=" => Some((6, BinOp::Ne)),
+        "<" => Some((7, BinOp::Lt)),
+        ">" => Some((7, BinOp::Gt)),
+        "<=" => Some((7, BinOp::Le)),
+        ">=" => Some((7, BinOp::Ge)),
+        "<<" => Some((8, BinOp::Shl)),
+        ">>" => Some((8, BinOp::Shr)),
+        "+" => Some((9, BinOp::Add)),
+        "-" => Some((9, BinOp::Sub)),
+        "*" => Some((10, BinOp::Mul)),
+        "/" => Some((10, BinOp::Div)),
+        "%" => Some((10, BinOp::Rem)),
+        _ => None,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Tok>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Tok> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn expect(&mut self, expected: Tok) -> Result<(), String> {
+        match self.next() {
+            Some(tok) if tok == expected => Ok(()),
+            Some(tok) => Err(format!("expected {:?}, found {:?}", expected, tok)),
+            None => Err(format!("expected {:?}, found end of expression", expected)),
+        }
+    }
+
+    // 優先順位が min_prec 以上の二項演算子をまとめる (すべて左結合)
+    fn parse_binary(&mut self, min_prec: u8) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(Tok::Op(op)) = self.peek() {
+            let Some((prec, op)) = binary_op(op) else { break };
+            if prec < min_prec {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.parse_binary(prec + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Some(Tok::Op("-")) => UnaryOp::Neg,
+            Some(Tok::Op("!")) => UnaryOp::Not,
+            Some(Tok::Op("~")) => UnaryOp::BitNot,
+            _ => return self.parse_power(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    // a ** b ** c は a ** (b ** c)。-2 ** 2 は -(2 ** 2)
+    fn parse_power(&mut self) -> Result<Expr, String> {
+        let base = self.parse_postfix()?;
+        if let Some(Tok::Op("**")) = self.peek() {
+            self.pos += 1;
+            let exponent = self.parse_unary()?;
+            return Ok(Expr::Binary(BinOp::Pow, Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    // 添字 (xs[0], xs[i][j])
+    fn parse_postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_primary()?;
+        while let Some(Tok::LBracket) = self.peek() {
+            self.pos += 1;
+            let index = self.parse_binary(0)?;
+            self.expect(Tok::RBracket)?;
+            expr = Expr::Index(Box::new(expr), Box::new(index));
+        }
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Tok::Integer(value)) => Ok(Expr::Value(Token::Integer(value))),
+            Some(Tok::Float(value)) => Ok(Expr::Value(Token::Float(value))),
+            Some(Tok::String(string)) => Ok(Expr::Value(Token::String(string))),
+            Some(Tok::Interp(parts)) => Ok(Expr::Interp(parts)),
+            Some(Tok::Var(name)) => Ok(Expr::Var(name)),
+            Some(Tok::Ident(name)) => {
+                if let Some(Tok::LParen) = self.peek() {
+                    self.pos += 1;
+                    let args = self.parse_args()?;
+                    Ok(Expr::Call(name, args))
+                } else {
+                    Ok(Expr::Var(name))
+                }
+            }
+            Some(Tok::LParen) => {
+                let expr = self.parse_binary(0)?;
+                self.expect(Tok::RParen)?;
+                Ok(expr)
+            }
+            Some(Tok::LBracket) => Ok(Expr::List(self.parse_list(Tok::RBracket)?)),
+            Some(Tok::LBrace) => self.parse_map(),
+            Some(tok) => Err(format!("unexpected {:?} in expression", tok)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+
+    // 関数の引数 (開き括弧の後から閉じ括弧まで)
+    fn parse_args(&mut self) -> Result<Vec<Expr>, String> {
+        self.parse_list(Tok::RParen)
+    }
+
+    // {キー: 値, ...} (開き括弧の後から)
+    fn parse_map(&mut self) -> Result<Expr, String> {
+        let mut entries = Vec::new();
+        if let Some(Tok::RBrace) = self.peek() {
+            self.pos += 1;
+            return Ok(Expr::Map(entries));
+        }
+        loop {
+            let key = self.parse_binary(0)?;
+            self.expect(Tok::Colon)?;
+            entries.push((key, self.parse_binary(0)?));
+            match self.next() {
+                Some(Tok::Comma) => continue,
+                Some(Tok::RBrace) => return Ok(Expr::Map(entries)),
+                Some(tok) => return Err(format!("expected ',' or '}}', found {:?}", tok)),
+                None => return Err(String::from("missing '}'")),
+            }
+        }
+    }
+
+    // カンマ区切りの式を close まで読む
+    fn parse_list(&mut self, close: Tok) -> Result<Vec<Expr>, String> {
+        let mut items = Vec::new();
+        if self.peek() == Some(&close) {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(self.parse_binary(0)?);
+            match self.next() {
+                Some(Tok::Comma) => continue,
+                Some(tok) if tok == close => return Ok(items),
+                Some(tok) => return Err(format!("expected ',' or {:?}, found {:?}", close, tok)),
+                None => return Err(format!("missing {:?}", close)),
+            }
+        }
+    }
+}
+
+pub fn parse(src: &str) -> Result<Expr, String> {
+    let mut parser = Parser { tokens: tokenize(src)?, pos: 0 };
+    let expr = parser.parse_binary(0)?;
+    if let Some(tok) = parser.peek() {
+        return Err(format!("unexpected {:?} after expression", tok));
+    }
+    Ok(expr)
+}
+
+pub fn eval(expr: &Expr, env: &dyn Env) -> Result<Token, String> {
+    match expr {
+        Expr::Value(value) => Ok(value.clone()),
+        Expr::Interp(parts) => interpolate(parts, env).map(Token::String),
+        Expr::List(items) => Ok(Token::List(items.iter().map(|item| eval(item, env)).collect::<Result<_, _>>()?)),
+        Expr::Map(entries) => {
+            let mut map = Token::Map(Vec::new());
+            for (key, value) in entries {
+                map = map_set(map, &eval(key, env)?, eval(value, env)?)?;
+            }
+            Ok(map)
+        }
+        Expr::Index(target, index) => self::index(eval(target, env)?, eval(index, env)?),
+        Expr::Var(name) => env.get_var(name),
+        Expr::Unary(op, operand) => unary(*op, eval(operand, env)?),
+        // && と || は左辺で結果が決まれば右辺を評価しない
+        Expr::Binary(op @ (BinOp::And | BinOp::Or), lhs, rhs) => {
+            let lhs = truthy(&eval(lhs, env)?)?;
+            if (*op == BinOp::And && !lhs) || (*op == BinOp::Or && lhs) {
+                return Ok(Token::Integer(lhs as i64));
+            }
+            Ok(Token::Integer(truthy(&eval(rhs, env)?)? as i64))
+        }
+        Expr::Binary(op, lhs, rhs) => binary(*op, eval(lhs, env)?, eval(rhs, env)?),
+        Expr::Call(name, args) => {
+            let args = args.iter().map(|arg| eval(arg, env)).collect::<Result<Vec<_>, _>>()?;
+            match builtin(name, &args) {
+                Some(result) => result,
+                None => env.call(name, args),
+            }
+        }
+    }
+}
+
+// ^IF^ と同じく正の数を真とみなす
+fn truthy(value: &Token) -> Result<bool, String> {
+    match value {
+        Token::Integer(value) => Ok(*value > 0),
+        Token::Float(value) => Ok(*value > 0.0),
+        value => Err(format!("expected a condition, found {:?}", value)),
+    }
+}
+
+// 数値を f64 にする
+fn to_float(value: &Token) -> Option<f64> {
+    match value {
+        Token::Integer(value) => Some(*value as f64),
+        Token::Float(value) => Some(*value),
+        _ => None,
+    }
+}
+
+// 数値どうしなら整数と浮動小数点数も値で比べる (1 と 1.0 は等しい)
+// マップはキーの順番によらず中身で比べる
+pub fn equals(lhs: &Token, rhs: &Token) -> bool {
+    match (lhs, rhs) {
+        (Token::List(a), Token::List(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equals(a, b)),
+        (Token::Map(a), Token::Map(b)) => {
+            a.len() == b.len() && a.iter().all(|(key, value)| b.iter().any(|(k, v)| k == key && equals(value, v)))
+        }
+        (Token::Integer(_), Token::Float(_)) | (Token::Float(_), Token::Integer(_)) => to_float(lhs) == to_float(rhs),
+        _ => lhs == rhs,
+    }
+}
+
+// 埋め込み文字列を組み立てる
+pub fn interpolate(parts: &[InterpPart], env: &dyn Env) -> Result<String, String> {
+    let mut result = String::new();
+    for part in parts {
+        let value = match part {
+            InterpPart::Text(text) => {
+                result.push_str(text);
+                continue;
+            }
+            InterpPart::Var(name) => env.get_var(name)?,
+            InterpPart::Expr(src) => eval(&parse(src)?, env)?,
+        };
+        match display(&value) {
+            Some(text) => result.push_str(&text),
+            None => return Err(format!("cannot embed {:?} in a string", value)),
+        }
+    }
+    Ok(result)
+}
+
+// 文字列, 数値, リストを表示用の文字列にする。表示できない値なら None
+pub fn display(value: &Token) -> Option<String> {
+    match value {
+        Token::String(string) => Some(string.clone()),
+        _ => repr(value),
+    }
+}
+
+// リストの中身のように、文字列を引用符付きで表す
+pub fn repr(value: &Token) -> Option<String> {
+    match value {
+        Token::String(string) => Some(format!("{:?}", string)),
+        Token::Integer(value) => Some(value.to_string()),
+        Token::Float(value) => Some(format_float(*value, None)),
+        Token::List(items) => Some(format!("[{}]", items.iter().map(repr).collect::<Option<Vec<_>>>()?.join(", "))),
+        Token::Map(entries) => Some(format!(
+            "{{{}}}",
+            entries.iter().map(|(key, value)| Some(format!("{:?}: {}", key, repr(value)?))).collect::<Option<Vec<_>>>()?.join(", ")
+        )),
+        _ => None,
+    }
+}
+
+fn map_key(key: &Token) -> Result<&str, String> {
+    match key {
+        Token::String(key) => Ok(key),
+        key => Err(format!("map keys must be strings, found {:?}", key)),
+    }
+}
+
+// キーに値を入れた新しいマップを返す。既にあるキーは位置を変えずに値だけ替える
+pub fn map_set(map: Token, key: &Token, value: Token) -> Result<Token, String> {
+    let Token::Map(mut entries) = map else {
+        return Err(format!("expected a map, found {:?}", map));
+    };
+    let key = map_key(key)?;
+    match entries.iter_mut().find(|(k, _)| k == key) {
+        Some(entry) => entry.1 = value,
+        None => entries.push((key.to_string(), value)),
+    }
+    Ok(Token::Map(entries))
+}
+
+// キーを消した新しいマップを返す
+pub fn map_delete(map: Token, key: &Token) -> Result<Token, String> {
+    let Token::Map(mut entries) = map else {
+        return Err(format!("expected a map, found {:?}", map));
+    };
+    let key = map_key(key)?;
+    entries.retain(|(k, _)| k != key);
+    Ok(Token::Map(entries))
+}
+
+// 添字で要素を取り出す。負の添字は末尾から数える
+pub fn index(target: Token, index: Token) -> Result<Token, String> {
+    match (&target, &index) {
+        (Token::List(items), Token::Integer(i)) => Ok(items[position(items.len(), *i)?].clone()),
+        (Token::Map(entries), Token::String(key)) => match entries.iter().find(|(k, _)| k == key) {
+            Some((_, value)) => Ok(value.clone()),
+            None => Err(format!("key not found: {:?}", key)),
+        },
+        (Token::String(string), Token::Integer(i)) => {
+            let chars: Vec<char> = string.chars().collect();
+            Ok(Token::String(chars[position(chars.len(), *i)?].to_string()))
+        }
+        _ => Err(format!("cannot index {:?} with {:?}", target, index)),
+    }
+}
+
+// 浮動小数点数を表示用の文字列にする。precision があればその桁数で丸める
+pub fn format_float(value: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(precision) => format!("{:.*}", precision, value),
+        None if value.is_finite() && value.fract() == 0.0 => format!("{:.1}", value),
+        None => format!("{}", value),
+    }
+}
+
+// 外側の状態に依らない組み込み関数。該当する関数がなければ None
+// 組み込み関数の名前か (同じ名前の関数は定義できない)
+pub fn is_builtin(name: &str) -> bool {
+    builtin(name, &[]).is_some()
+}
+
+fn builtin(name: &str, args: &[Token]) -> Option<Result<Token, String>> {
+    let result = match (name, args) {
+        ("min" | "max", [first, rest @ ..]) => rest.iter().try_fold(first.clone(), |acc, value| {
+            let op = if name == "min" { BinOp::Lt } else { BinOp::Gt };
+            Ok(if truthy(&binary(op, value.clone(), acc.clone())?)? { value.clone() } else { acc })
+        }),
+        ("abs", [Token::Integer(value)]) => value.checked_abs().map(Token::Integer).ok_or_else(|| format!("integer overflow: abs({})", value)),
+        ("abs", [Token::Float(value)]) => Ok(Token::Float(value.abs())),
+
+        // 整数と浮動小数点数の変換 (文字列なら数値として読む)
+        ("int", [Token::Integer(value)]) => Ok(Token::Integer(*value)),
+        ("int", [Token::Float(value)]) => float_to_int(value.trunc()),
+        ("int", [Token::String(string)]) => string.trim().parse().map(Token::Integer).map_err(|_| format!("not an integer: {:?}", string)),
+        ("float", [Token::String(string)]) => string.trim().parse().map(Token::Float).map_err(|_| format!("not a number: {:?}", string)),
+        ("float", [value]) => to_float(value).map(Token::Float).ok_or_else(|| format!("float expects a number, found {:?}", value)),
+        ("round" | "floor" | "ceil", [Token::Integer(value)]) => Ok(Token::Integer(*value)),
+        ("round", [Token::Float(value)]) => float_to_int(value.round()),
+        ("floor", [Token::Float(value)]) => float_to_int(value.floor()),
+        ("ceil", [Token::Float(value)]) => float_to_int(value.ceil()),
+
+        // 小数点以下の桁数を指定して文字列にする
+        ("fixed", [value, Token::Integer(digits)]) if *digits >= 0 => match to_float(value) {
+            Some(value) => Ok(Token::String(format_float(value, Some(*digits as usize)))),
+            None => Err(format!("fixed expects a number, found {:?}", value)),
+        },
+
+        // 文字列の操作。位置と長さは文字単位
+        ("str", [Token::Code(code)]) => Ok(Token::String(code.clone())),
+        ("str", [value]) => display(value).map(Token::String).ok_or_else(|| format!("cannot convert {:?} to a string", value)),
+        ("len", [Token::String(string)]) => Ok(Token::Integer(string.chars().count() as i64)),
+        ("len", [Token::List(items)]) => Ok(Token::Integer(items.len() as i64)),
+        ("len", [Token::Map(entries)]) => Ok(Token::Integer(entries.len() as i64)),
+        ("substr", [Token::String(string), Token::Integer(start), Token::Integer(len)]) => {
+            let start = char_index(string, *start);
+            Ok(Token::String(string.chars().skip(start).take((*len).max(0) as usize).collect()))
+        }
+        ("slice", [Token::String(string), Token::Integer(start), Token::Integer(end)]) => {
+            let (start, end) = (char_index(string, *start), char_index(string, *end));
+            Ok(Token::String(string.chars().skip(start).take(end.saturating_sub(start)).collect()))
+        }
+        ("slice", [Token::List(items), Token::Integer(start), Token::Integer(end)]) => {
+            let (start, end) = (list_index(items.len(), *start), list_index(items.len(), *end));
+            Ok(Token::List(items[start..end.max(start)].to_vec()))
+        }
+
+        // リストの操作。push と pop は新しいリストを返す (変数を書き換えるのは ^PU^ と ^PO^)
+        ("push", [Token::List(items), value]) => {
+            let mut items = items.clone();
+            items.push(value.clone());
+            Ok(Token::List(items))
+        }
+        ("pop", [Token::List(items)]) => match items.split_last() {
+            Some((_, rest)) => Ok(Token::List(rest.to_vec())),
+            None => Err(String::from("pop from an empty list")),
+        },
+        // マップの操作。set と del は新しいマップを返す (変数を書き換えるのは ^PT^ と ^DL^)
+        ("get", [map @ Token::Map(_), key]) => index(map.clone(), key.clone()),
+        ("get", [Token::Map(entries), Token::String(key), default]) => {
+            Ok(entries.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone()).unwrap_or_else(|| default.clone()))
+        }
+        ("set", [map @ Token::Map(_), key, value]) => map_set(map.clone(), key, value.clone()),
+        ("del", [map @ Token::Map(_), key]) => map_delete(map.clone(), key),
+        ("has", [Token::Map(entries), Token::String(key)]) => Ok(Token::Integer(entries.iter().any(|(k, _)| k == key) as i64)),
+        ("keys", [Token::Map(entries)]) => Ok(Token::List(entries.iter().map(|(key, _)| Token::String(key.clone())).collect())),
+        ("values", [Token::Map(entries)]) => Ok(Token::List(entries.iter().map(|(_, value)| value.clone()).collect())),
+
+        ("split", [Token::String(string), Token::String(sep)]) => {
+            if sep.is_empty() {
+                return Some(Err(String::from("split: separator must not be empty")));
+            }
+            Ok(Token::List(string.split(sep.as_str()).map(|item| Token::String(item.to_string())).collect()))
+        }
+        ("join", [Token::List(items), Token::String(sep)]) => items
+            .iter()
+            .map(|item| display(item).ok_or_else(|| format!("cannot join {:?}", item)))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|items| Token::String(items.join(sep))),
+        ("indexof", [Token::String(string), Token::String(pat)]) => Ok(Token::Integer(match string.find(pat.as_str()) {
+            Some(pos) => string[..pos].chars().count() as i64,
+            None => -1,
+        })),
+        ("trim", [Token::String(string)]) => Ok(Token::String(string.trim().to_string())),
+        ("replace", [Token::String(string), Token::String(from), Token::String(to)]) => {
+            if from.is_empty() {
+                return Some(Err(String::from("replace: pattern must not be empty")));
+            }
+            Ok(Token::String(string.replace(from.as_str(), to)))
+        }
+        ("repeat", [Token::String(string), Token::Integer(count)]) if *count >= 0 => Ok(Token::String(string.repeat(*count as usize))),
+        ("splitcount", [Token::String(string), Token::String(sep)]) => {
+            if sep.is_empty() {
+                return Some(Err(String::from("splitcount: separator must not be empty")));
+            }
+            Ok(Token::Integer(string.split(sep.as_str()).count() as i64))
+        }
+        ("upper", [Token::String(string)]) => Ok(Token::String(string.to_uppercase())),
+        ("lower", [Token::String(string)]) => Ok(Token::String(string.to_lowercase())),
+
+        // 文字列をコードにする (^EV^ や M で実行できる)。str() で文字列に戻せる
+        ("code", [Token::String(code) | Token::Code(code)]) => Ok(Token::Code(code.clone())),
+
+        ("min" | "max" | "abs" | "int" | "float" | "round" | "floor" | "ceil" | "fixed"
+        | "str" | "len" | "substr" | "slice" | "indexof" | "trim" | "replace" | "repeat" | "splitcount" | "upper" | "lower"
+        | "push" | "pop" | "split" | "join" | "get" | "set" | "del" | "has" | "keys" | "values" | "code", _) => {
+            Err(format!("invalid arguments for {}: {:?}", name, args))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+// 負の添字を末尾からの位置にする
+fn from_end(len: usize, index: i64) -> i64 {
+    if index < 0 { len as i64 + index } else { index }
+}
+
+// 要素の位置。負なら末尾から数える。範囲外はエラー (xs[i] や ^PT^ / ^DL^)
+pub fn position(len: usize, index: i64) -> Result<usize, String> {
+    let position = from_end(len, index);
+    if position < 0 || position >= len as i64 {
+        return Err(format!("index out of range: {} (length {})", index, len));
+    }
+    Ok(position as usize)
+}
+
+// 文字の位置。負なら末尾から数える。範囲外は端に丸める (slice や substr)
+fn char_index(string: &str, index: i64) -> usize {
+    list_index(string.chars().count(), index)
+}
+
+fn list_index(len: usize, index: i64) -> usize {
+    from_end(len, index).clamp(0, len as i64) as usize
+}
+
+fn float_to_int(value: f64) -> Result<Token, String> {
+    if value.is_finite() && value >= i64::MIN as f64 && value < i64::MAX as f64 {
+        Ok(Token::Integer(value as i64))
+    } else {
+        Err(format!("cannot convert {} to an integer", value))
+    }
+}
+
+fn unary(op: UnaryOp, value: Token) -> Result<Token, String> {
+    match (op, value) {
+        (UnaryOp::Neg, Token::Integer(value)) => value.checked_neg().map(Token::Integer).ok_or_else(|| format!("integer overflow: -{}", value)),
+        (UnaryOp::Not, value) => Ok(Token::Integer(!truthy(&value)? as i64)),
+        (UnaryOp::Neg, Token::Float(value)) => Ok(Token::Float(-value)),
+        (UnaryOp::BitNot, Token::Integer(value)) => Ok(Token::Integer(!value)),
+        (op, value) => Err(format!("cannot apply {:?} to {:?}", op, value)),
+    }
+}
+
+pub fn binary(op: BinOp, lhs: Token, rhs: Token) -> Result<Token, String> {
+    // リストの連結
+    if let (Token::List(a), Token::List(b), BinOp::Add) = (&lhs, &rhs, op) {
+        return Ok(Token::List(a.iter().chain(b).cloned().collect()));
+    }
+
+    // リストとマップは中身で比べる
+    if let (Token::List(_), Token::List(_)) | (Token::Map(_), Token::Map(_)) = (&lhs, &rhs) {
+        match op {
+            BinOp::Eq => return Ok(Token::Integer(equals(&lhs, &rhs) as i64)),
+            BinOp::Ne => return Ok(Token::Integer(!equals(&lhs, &rhs) as i64)),
+            _ => {}
+        }
+    }
+
+    // 文字列の連結と比較
+    match (&lhs, &rhs) {
+        (Token::String(_), _) | (_, Token::String(_)) if op == BinOp::Add => {
+            if let (Some(a), Some(b)) = (display(&lhs), display(&rhs)) {
+                return Ok(Token::String(a + &b));
+            }
+        }
+        (Token::String(a), Token::String(b)) => {
+            let value = match op {
+                BinOp::Eq => a == b,
+                BinOp::Ne => a != b,
+                BinOp::Lt => a < b,
+                BinOp::Gt => a > b,
+                BinOp::Le => a <= b,
+                BinOp::Ge => a >= b,
+                _ => return Err(format!("cannot apply {} to strings: {:?} {} {:?}", op.symbol(), a, op.symbol(), b)),
+            };
+            return Ok(Token::Integer(value as i64));
+        }
+        _ => {}
+    }
+
+    // どちらかが浮動小数点数なら浮動小数点数で計算する
+    if let (Token::Float(_), Token::Integer(_) | Token::Float(_)) | (Token::Integer(_), Token::Float(_)) = (&lhs, &rhs) {
+        return binary_float(op, to_float(&lhs).unwrap_or(0.0), to_float(&rhs).unwrap_or(0.0));
+    }
+
+    let (Token::Integer(a), Token::Integer(b)) = (&lhs, &rhs) else {
+        return Err(format!("cannot apply {:?} to {:?} and {:?}", op, lhs, rhs));
+    };
+    let (a, b) = (*a, *b);
+
+    // 桁あふれやゼロ除算は計算に使った値と一緒にエラーにする
+    let overflow = || format!("integer overflow: {} {} {}", a, op.symbol(), b);
+    let value = match op {
+        BinOp::Add => a.checked_add(b).ok_or_else(overflow)?,
+        BinOp::Sub => a.checked_sub(b).ok_or_else(overflow)?,
+        BinOp::Mul => a.checked_mul(b).ok_or_else(overflow)?,
+        BinOp::Div | BinOp::Rem if b == 0 => {
+            return Err(format!("{} by zero: {} {} {}", if op == BinOp::Div { "division" } else { "modulo" }, a, op.symbol(), b));
+        }
+        BinOp::Div => a.checked_div(b).ok_or_else(overflow)?,
+        BinOp::Rem => a.checked_rem(b).ok_or_else(overflow)?,
+        BinOp::Eq => (a == b) as i64,
+        BinOp::Ne => (a != b) as i64,
+        BinOp::Lt => (a < b) as i64,
+        BinOp::Gt => (a > b) as i64,
+        BinOp::Le => (a <= b) as i64,
+        BinOp::Ge => (a >= b) as i64,
+        BinOp::And => (a > 0 && b > 0) as i64,
+        BinOp::Or => (a > 0 || b > 0) as i64,
+        BinOp::BitAnd => a & b,
+        BinOp::BitOr => a | b,
+        BinOp::BitXor => a ^ b,
+        BinOp::Shl | BinOp::Shr if !(0..64).contains(&b) => {
+            return Err(format!("shift out of range: {} {} {}", a, op.symbol(), b));
+        }
+        // 戻したときに元の値にならなければ、桁があふれて消えている
+        BinOp::Shl => Some(a << b).filter(|value| value >> b == a).ok_or_else(overflow)?,
+        BinOp::Shr => a.checked_shr(b as u32).ok_or_else(overflow)?,
+        BinOp::Pow => {
+            if b < 0 {
+                return Err(format!("negative exponent: {} ** {}", a, b));
+            }
+            u32::try_from(b).ok().and_then(|b| a.checked_pow(b)).ok_or_else(overflow)?
+        }
+    };
+    Ok(Token::Integer(value))
+}
+
+fn binary_float(op: BinOp, a: f64, b: f64) -> Result<Token, String> {
+    let value = match op {
+        BinOp::Add => a + b,
+        BinOp::Sub => a - b,
+        BinOp::Mul => a * b,
+        BinOp::Div | BinOp::Rem if b == 0.0 => {
+            return Err(format!("{} by zero: {} {} {}", if op == BinOp::Div { "division" } else { "modulo" }, a, op.symbol(), b));
+        }
+        BinOp::Div => a / b,
+        BinOp::Rem => a % b,
+        BinOp::Pow => a.powf(b),
+        BinOp::Eq => return Ok(Token::Integer((a == b) as i64)),
+        BinOp::Ne => return Ok(Token::Integer((a != b) as i64)),
+        BinOp::Lt => return Ok(Token::Integer((a < b) as i64)),
+        BinOp::Gt => return Ok(Token::Integer((a > b) as i64)),
+        BinOp::Le => return Ok(Token::Integer((a <= b) as i64)),
+        BinOp::Ge => return Ok(Token::Integer((a >= b) as i64)),
+        BinOp::And => return Ok(Token::Integer((a > 0.0 && b > 0.0) as i64)),
+        BinOp::Or => return Ok(Token::Integer((a > 0.0 || b > 0.0) as i64)),
+        BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Shl | BinOp::Shr => {
+            return Err(format!("cannot apply {} to floats: {} {} {}", op.symbol(), a, op.symbol(), b));
+        }
+    };
+    Ok(Token::Float(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 変数は x = 10 だけ、関数は組み込みだけの環境
+    struct TestEnv;
+
+    impl Env for TestEnv {
+        fn get_var(&self, name: &str) -> Result<Token, String> {
+            match name {
+                "x" => Ok(Token::Integer(10)),
+                _ => Err(format!("variables do not exist: {}", name)),
+            }
+        }
+
+        fn call(&self, name: &str, _args: Vec<Token>) -> Result<Token, String> {
+            Err(format!("unknown function: {}", name))
+        }
+    }
+
+    fn run(src: &str) -> Result<Token, String> {
+        parse(src).and_then(|ast| eval(&ast, &TestEnv))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(run("1 + 2 * 3"), Ok(Token::Integer(7)));
+        assert_eq!(run("(1 + 2) * 3"), Ok(Token::Integer(9)));
+        assert_eq!(run("1 + 2 < 4 && 3 == 3"), Ok(Token::Integer(1)));
+        assert_eq!(run("1 | 2 ^ 3 & 1"), Ok(Token::Integer(1 | (2 ^ (3 & 1)))));
+        assert_eq!(run("1 << 2 + 1"), Ok(Token::Integer(8)));
+        assert_eq!(run("x - 1"), Ok(Token::Integer(9)));
+    }
+
+    #[test]
+    fn left_associative() {
+        assert_eq!(run("10 - 3 - 2"), Ok(Token::Integer(5)));
+        assert_eq!(run("100 / 10 / 5"), Ok(Token::Integer(2)));
+        assert_eq!(run("17 % 5 % 3"), Ok(Token::Integer(2)));
+    }
+
+    #[test]
+    fn power() {
+        // ** は右結合で、単項のマイナスより強い
+        assert_eq!(run("2 ** 3 ** 2"), Ok(Token::Integer(512)));
+        assert_eq!(run("-2 ** 2"), Ok(Token::Integer(-4)));
+        assert_eq!(run("(-2) ** 2"), Ok(Token::Integer(4)));
+        assert_eq!(run("2 * 3 ** 2"), Ok(Token::Integer(18)));
+        assert!(run("2 ** -1").is_err());
+    }
+
+    #[test]
+    fn short_circuit() {
+        // 右辺は評価されないので未定義の変数でもエラーにならない
+        assert_eq!(run("0 && y"), Ok(Token::Integer(0)));
+        assert_eq!(run("1 || y"), Ok(Token::Integer(1)));
+        assert!(run("1 && y").is_err());
+    }
+
+    #[test]
+    fn calls_and_errors() {
+        assert_eq!(run("max(1, x, 3) + min(4, 2)"), Ok(Token::Integer(12)));
+        assert!(run("nothing(1)").is_err());
+        assert!(run("1 +").is_err());
+        assert!(run("(1 + 2").is_err());
+        assert!(run("1 / 0").is_err());
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(run("1 << 62"), Ok(Token::Integer(1 << 62)));
+        assert_eq!(run("-1 << 63"), Ok(Token::Integer(i64::MIN)));
+        assert_eq!(run("1 << 63"), Err(String::from("integer overflow: 1 << 63")));
+        assert_eq!(run("3 << 62"), Err(String::from("integer overflow: 3 << 62")));
+        assert_eq!(run("1 << 64"), Err(String::from("shift out of range: 1 << 64")));
+        assert_eq!(run("9223372036854775807 + 1"), Err(String::from("integer overflow: 9223372036854775807 + 1")));
+        assert_eq!(run("2 ** 63"), Err(String::from("integer overflow: 2 ** 63")));
+        assert_eq!(run("9223372036854775808"), Err(String::from("number out of range: 9223372036854775808")));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(run("1 / 0"), Err(String::from("division by zero: 1 / 0")));
+        assert_eq!(run("x % 0"), Err(String::from("modulo by zero: 10 % 0")));
+        assert_eq!(run("(-9223372036854775807 - 1) / -1"), Err(String::from("integer overflow: -9223372036854775808 / -1")));
+    }
+}
@@ -6,10 +6,43 @@ pub enum Token {
     MultiLengthCommand(String),
     String(String),
     Var(String),
-    Integer(i32),
+    Integer(i64),
+    Float(f64),
     Expr(String),
     Code(String),
     Comment(String),
+    Interp(Vec<InterpPart>),
+    List(Vec<Token>),
+    // キーは挿入順に並ぶ
+    Map(Vec<(String, Token)>),
+    // 読めなかったリテラル。評価したときにこのメッセージでエラーにする
+    Invalid(String),
+    // ^DF^ で定義した関数。captured は定義したときの関数のローカル変数 (クロージャー)
+    Func { params: Vec<String>, code: String, captured: Vec<(String, Token)> },
+}
+
+// 埋め込み文字列 (`...`) の部品
+#[derive(Debug, PartialEq, Clone)]
+pub enum InterpPart {
+    Text(String),
+    Var(String),
+    Expr(String),
+}
+
+// *1; は整数、*1.5; や *1e3; は浮動小数点数
+// 範囲外や数字でないものは Invalid にして、評価したときにエラーにする
+fn number(arg: &str) -> Token {
+    if arg.contains(['.', 'e', 'E']) {
+        arg.parse().map(Token::Float).unwrap_or_else(|_| Token::Invalid(format!("invalid number: {}", arg)))
+    } else {
+        match arg.parse() {
+            Ok(value) => Token::Integer(value),
+            Err(_) if !arg.is_empty() && arg.trim_start_matches(['-', '+']).chars().all(|c| c.is_ascii_digit()) => {
+                Token::Invalid(format!("number out of range: {}", arg))
+            }
+            Err(_) => Token::Invalid(format!("invalid number: {}", arg)),
+        }
+    }
 }
 
 pub struct Lexer {
@@ -24,142 +57,200 @@ impl Lexer {
     }
 
     pub fn next_token(&mut self) -> Option<Token> {
-        while let Some(c) = self.input.pop_front() {
-            match c {
-                '#' => {
-                    let mut arg = String::new();
-                    while let Some(nc) = self.input.pop_front() {
-                        if nc == '#' {
-                            return Some(Token::Comment(arg));
-                        } else {
-                            arg.push(nc);
-                        }
+        let c = self.input.pop_front()?;
+        match c {
+            '#' => {
+                let mut arg = String::new();
+                while let Some(nc) = self.input.pop_front() {
+                    if nc == '#' {
+                        return Some(Token::Comment(arg));
+                    } else {
+                        arg.push(nc);
                     }
-                    return Some(Token::Comment(arg));
                 }
-                '(' => {
-                    let mut arg = String::new();
-                    let mut escape_mode = false;
-                    let mut depth = 1; // ネストの深さをカウント
-                    while let Some(nc) = self.input.pop_front() {
-                        if nc == '\\' && !escape_mode {
-                            escape_mode = true;
-                        } else if escape_mode {
-                            match nc {
-                                '\\' => arg.push('\\'),
-                                'n' => arg.push('\n'),
-                                _ => arg.push(nc),
-                            }
-                            escape_mode = false;
-                        } else if nc == '(' {
-                            depth += 1; // 入れ子が深くなる
-                            arg.push(nc);
-                        } else if nc == ')' {
-                            depth -= 1; // 入れ子が浅くなる
-                            if depth == 0 {
-                                return Some(Token::Expr(arg));
-                            }
-                            arg.push(nc);
-                        } else {
-                            arg.push(nc);
+                Some(Token::Comment(arg))
+            }
+            '(' => {
+                let mut arg = String::new();
+                let mut depth = 1; // ネストの深さをカウント
+                let mut in_string = false; // 文字列の中の括弧は数えない
+                while let Some(nc) = self.input.pop_front() {
+                    if nc == '\\' {
+                        // エスケープは中身を字句解析するときに処理するので、そのまま残す
+                        arg.push(nc);
+                        if let Some(escaped) = self.input.pop_front() {
+                            arg.push(escaped);
+                        }
+                    } else if nc == '"' {
+                        in_string = !in_string;
+                        arg.push(nc);
+                    } else if in_string {
+                        arg.push(nc);
+                    } else if nc == '(' {
+                        depth += 1; // 入れ子が深くなる
+                        arg.push(nc);
+                    } else if nc == ')' {
+                        depth -= 1; // 入れ子が浅くなる
+                        if depth == 0 {
+                            return Some(Token::Expr(arg));
                         }
+                        arg.push(nc);
+                    } else {
+                        arg.push(nc);
                     }
-                    return Some(Token::Expr(arg)); // `{` の対応が閉じていない場合
                 }
-                '*' => {
-                    let mut arg = String::new();
-                    while let Some(nc) = self.input.pop_front() {
-                        if nc == ';' {
-                            return Some(Token::Integer(arg.parse().unwrap_or(0)));
-                        } else {
-                            arg.push(nc);
-                        }
+                Some(Token::Expr(arg)) // `{` の対応が閉じていない場合
+            }
+            '*' => {
+                let mut arg = String::new();
+                while let Some(nc) = self.input.pop_front() {
+                    if nc == ';' {
+                        return Some(number(&arg));
+                    } else {
+                        arg.push(nc);
                     }
-                    return Some(Token::Integer(arg.parse().unwrap_or(0)));
                 }
-                '{' => {
-                    let mut arg = String::new();
-                    let mut escape_mode = false;
-                    let mut depth = 1; // ネストの深さをカウント
-                    while let Some(nc) = self.input.pop_front() {
-                        if nc == '\\' && !escape_mode {
-                            escape_mode = true;
-                        } else if escape_mode {
-                            match nc {
-                                '\\' => arg.push('\\'),
-                                'n' => arg.push('\n'),
-                                _ => arg.push(nc),
-                            }
-                            escape_mode = false;
-                        } else if nc == '{' {
-                            depth += 1; // 入れ子が深くなる
-                            arg.push(nc);
-                        } else if nc == '}' {
-                            depth -= 1; // 入れ子が浅くなる
-                            if depth == 0 {
-                                return Some(Token::Code(arg));
-                            }
-                            arg.push(nc);
-                        } else {
-                            arg.push(nc);
+                Some(number(&arg))
+            }
+            '{' => {
+                let mut arg = String::new();
+                let mut depth = 1; // ネストの深さをカウント
+                while let Some(nc) = self.input.pop_front() {
+                    if nc == '\\' {
+                        // エスケープは中身を字句解析するときに処理するので、そのまま残す
+                        arg.push(nc);
+                        if let Some(escaped) = self.input.pop_front() {
+                            arg.push(escaped);
+                        }
+                    } else if nc == '{' {
+                        depth += 1; // 入れ子が深くなる
+                        arg.push(nc);
+                    } else if nc == '}' {
+                        depth -= 1; // 入れ子が浅くなる
+                        if depth == 0 {
+                            return Some(Token::Code(arg));
                         }
+                        arg.push(nc);
+                    } else {
+                        arg.push(nc);
                     }
-                    return Some(Token::Code(arg)); // `{` の対応が閉じていない場合
                 }
-                '^' => {
-                    let mut arg = String::new();
-                    while let Some(nc) = self.input.pop_front() {
-                        if nc == '^' {
-                            return Some(Token::MultiLengthCommand(arg));
-                        } else {
-                            arg.push(nc);
-                        }
+                Some(Token::Code(arg)) // `{` の対応が閉じていない場合
+            }
+            '^' => {
+                let mut arg = String::new();
+                while let Some(nc) = self.input.pop_front() {
+                    if nc == '^' {
+                        return Some(Token::MultiLengthCommand(arg));
+                    } else {
+                        arg.push(nc);
                     }
-                    return Some(Token::MultiLengthCommand(arg));
                 }
-                '"' => {
-                    let mut escape_mode = false;
-                    let mut arg = String::new();
-                    while let Some(nc) = self.input.pop_front() {
-                        if nc == '\\' && !escape_mode {
-                            escape_mode = true;
-                        } else if escape_mode {
-                            match nc {
-                                '\\' => arg.push('\\'),
-                                '"' => arg.push('"'),
-                                'n' => arg.push('\n'),
-                                _ => arg.push(nc),
+                Some(Token::MultiLengthCommand(arg))
+            }
+            // `Hello $name$ (1 + 2)` のように $...$ と (...) を評価時に埋め込む
+            // \$ と \( でそれぞれ $ と ( をそのまま書ける
+            '`' => {
+                let mut parts = Vec::new();
+                let mut text = String::new();
+                while let Some(nc) = self.input.pop_front() {
+                    match nc {
+                        '`' => break,
+                        '\\' => match self.input.pop_front() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(other) => text.push(other),
+                            None => text.push('\\'),
+                        },
+                        '$' => {
+                            let mut name = String::new();
+                            while let Some(nc) = self.input.pop_front() {
+                                if nc == '$' {
+                                    break;
+                                }
+                                name.push(nc);
+                            }
+                            parts.push(InterpPart::Text(std::mem::take(&mut text)));
+                            parts.push(InterpPart::Var(name));
+                        }
+                        '(' => {
+                            let mut expr = String::new();
+                            let mut depth = 1;
+                            let mut in_string = false;
+                            while let Some(nc) = self.input.pop_front() {
+                                if in_string {
+                                    if nc == '\\' {
+                                        expr.push(nc);
+                                        if let Some(escaped) = self.input.pop_front() {
+                                            expr.push(escaped);
+                                        }
+                                        continue;
+                                    }
+                                    in_string = nc != '"';
+                                } else if nc == '"' {
+                                    in_string = true;
+                                } else if nc == '(' {
+                                    depth += 1;
+                                } else if nc == ')' {
+                                    depth -= 1;
+                                    if depth == 0 {
+                                        break;
+                                    }
+                                }
+                                expr.push(nc);
                             }
-                            escape_mode = false;
-                        } else if nc == '"' {
-                            return Some(Token::String(arg));
-                        } else {
-                            arg.push(nc);
+                            parts.push(InterpPart::Text(std::mem::take(&mut text)));
+                            parts.push(InterpPart::Expr(expr));
                         }
+                        _ => text.push(nc),
                     }
-                    return Some(Token::String(arg));
                 }
-                '$' => {
-                    let mut arg = String::new();
-                    while let Some(nc) = self.input.pop_front() {
-                        if nc == '$' {
-                            return Some(Token::Var(arg));
-                        } else {
-                            arg.push(nc);
+                parts.push(InterpPart::Text(text));
+                parts.retain(|part| *part != InterpPart::Text(String::new()));
+                Some(Token::Interp(parts))
+            }
+            '"' => {
+                let mut escape_mode = false;
+                let mut arg = String::new();
+                while let Some(nc) = self.input.pop_front() {
+                    if nc == '\\' && !escape_mode {
+                        escape_mode = true;
+                    } else if escape_mode {
+                        match nc {
+                            '\\' => arg.push('\\'),
+                            '"' => arg.push('"'),
+                            'n' => arg.push('\n'),
+                            _ => arg.push(nc),
                         }
+                        escape_mode = false;
+                    } else if nc == '"' {
+                        return Some(Token::String(arg));
+                    } else {
+                        arg.push(nc);
+                    }
+                }
+                Some(Token::String(arg))
+            }
+            '$' => {
+                let mut arg = String::new();
+                while let Some(nc) = self.input.pop_front() {
+                    if nc == '$' {
+                        return Some(Token::Var(arg));
+                    } else {
+                        arg.push(nc);
                     }
-                    return Some(Token::Var(arg));
                 }
-                _ => return Some(Token::Command(c)),
+                Some(Token::Var(arg))
             }
+            _ => Some(Token::Command(c)),
         }
-        None
     }
     
+    // 空白とコメントは捨てる (末尾のコマンドかどうかの判定に数えないように)
     pub fn tokenize(&mut self) -> Vec<Token> {
         let mut tokens = Vec::new();
         while let Some(token) = self.next_token() {
-            if token != Token::Command(' ') && token != Token::Command('\n') {
+            if token != Token::Command(' ') && token != Token::Command('\n') && !matches!(token, Token::Comment(_)) {
                 tokens.push(token);
             }
         }
@@ -167,6 +258,7 @@ impl Lexer {
     }
 }
 
+#[allow(dead_code)]
 pub fn extract_argument(token: Token) -> Option<String> {
     match token {
         Token::String(arg) => Some(arg),
@@ -174,6 +266,7 @@ pub fn extract_argument(token: Token) -> Option<String> {
     }
 }
 
+#[allow(dead_code)]
 pub fn extract_var(token: Token) -> Option<String> {
     match token {
         Token::Var(arg) => Some(arg),
@@ -181,7 +274,8 @@ pub fn extract_var(token: Token) -> Option<String> {
     }
 }
 
-pub fn extract_integer(token: Token) -> Option<i32> {
+#[allow(dead_code)]
+pub fn extract_integer(token: Token) -> Option<i64> {
     match token {
         Token::Integer(arg) => Some(arg),
         _ => None,
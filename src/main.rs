@@ -1,37 +1,48 @@
 use std::fs;
-use std::io::{self, Read, Write};
+use std::io::Read;
 use std::env;
+use std::process;
+use std::thread;
 
 mod buffer;
+mod expr;
 mod lexer;
 mod pleco;
 
 fn main() {
+    let interpreter = match thread::Builder::new().stack_size(pleco::STACK_SIZE).spawn(start) {
+        Ok(handle) => handle,
+        Err(err) => {
+            eprintln!("[ PLECo Error ]\tcannot start the interpreter thread: {}", err);
+            process::exit(1);
+        }
+    };
+    // パニックしたときはメッセージが表示済みなので、終了コードだけ返す
+    if interpreter.join().is_err() {
+        process::exit(101);
+    }
+}
+
+fn start() {
 
     let args: Vec<String> = env::args().collect();
     let pleco = pleco::PLECo::new();
 
     if let Some(fpath) = args.get(1) {
-        match fs::File::open(fpath) {
-            Ok(mut file) => {
-                let mut plecorc = String::new();
-                let _ = file.read_to_string(&mut plecorc);
-                pleco.handle_command(&plecorc);
-            }, 
-            Err(_) => { }
+        if let Ok(mut file) = fs::File::open(fpath) {
+            let mut plecorc = String::new();
+            let _ = file.read_to_string(&mut plecorc);
+            pleco.execute(&plecorc);
         }
 
     }
     else {
 
         if let Some(plecorc) = env::home_dir() {
-            match fs::File::open(format!("{}/.plecorc", plecorc.to_str().unwrap_or("~"))) {
-                Ok(mut file) => {
-                    let mut plecorc = String::new();
-                    let _ = file.read_to_string(&mut plecorc);
-                    pleco.handle_command(&plecorc);
-                }, 
-                Err(_) => { }
+            if let Ok(mut file) = fs::File::open(format!("{}/.plecorc", plecorc.to_str().unwrap_or("~"))) {
+                let mut plecorc = String::new();
+                let _ = file.read_to_string(&mut plecorc);
+                pleco.execute(&plecorc);
             }
         }
 
@@ -1,36 +1,90 @@
 
 // PLECo II
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{self, Read, Write};
-use std::process::{self, exit};
+use std::process;
 use std::sync::{Arc, Mutex};
-use std::env::{self, args};
 use crate::lexer;
 use crate::buffer;
+use crate::expr;
 
-fn error(message: &str) {
+fn error(message: &str) -> ! {
     eprintln!("[ PLECo Error ]\t{}", message);
     process::exit(1);
 }
 
+// break / continue でループを抜けるとき、^RT^ で関数から戻るときの状態
+// TailCall は末尾での呼び出しで、呼び出し元のループが代わりに実行する (スタックを伸ばさない)
+// returning は ^RT^ による呼び出しで、その戻り値を関数の戻り値にする
+#[derive(Debug, Clone, PartialEq)]
+enum Flow {
+    Normal,
+    Break(Option<String>),
+    Continue(Option<String>),
+    Return(lexer::Token),
+    TailCall { name: String, callee: lexer::Token, args: Vec<lexer::Token>, returning: bool },
+}
+
+// ループや関数の外に残った break / continue / ^RT^
+fn stray_flow_error(flow: &Flow) -> ! {
+    match flow {
+        Flow::Break(Some(label)) | Flow::Continue(Some(label)) => error(&format!("no enclosing loop labelled '{}'", label)),
+        Flow::Return(_) | Flow::TailCall { returning: true, .. } => error("return outside of a function"),
+        _ => error("break or continue outside of a loop"),
+    }
+}
+
+// コマンドの引数になれるトークン
+fn is_operand(token: &lexer::Token) -> bool {
+    matches!(token, lexer::Token::Integer(_) | lexer::Token::Float(_) | lexer::Token::String(_) | lexer::Token::Interp(_) | lexer::Token::Var(_) | lexer::Token::Expr(_) | lexer::Token::Code(_) | lexer::Token::Invalid(_))
+}
+
+// インタープリターを動かすスレッドのスタックの大きさ
+// 関数やマクロの呼び出しは Rust のスタックを使うので、MAXDEPTH の上限まで届くように大きめに取る
+pub const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+// MAXDEPTH に設定できる上限 (STACK_SIZE に収まる深さ。デバッグビルドでも 5000 段ほどまでは溢れない)
+const MAX_DEPTH_LIMIT: i64 = 2000;
+
+// 関数呼び出し 1 回分のスコープ。callee は呼び出している関数 (自分自身の再帰呼び出し用)
+struct Frame {
+    vars: HashMap<String, lexer::Token>,
+    name: String,
+    callee: lexer::Token,
+}
 
 pub struct PLECo {
     buffer: Arc<Mutex<buffer::ViewBuffer>>,
     vars: Arc<Mutex<HashMap<String, lexer::Token>>>,
+    // ^CS^ で定義した定数の名前 (グローバル変数)
+    consts: Arc<Mutex<HashSet<String>>>,
+    // 関数呼び出しごとのローカル変数。空ならグローバル
+    frames: Arc<Mutex<Vec<Frame>>>,
+    flow: Arc<Mutex<Flow>>,
+    // 呼び出し中の関数とマクロの名前 (MAXDEPTH を超えたときに表示する)
+    calls: Arc<Mutex<Vec<String>>>,
+    // 次に実行するコードが関数やマクロの末尾にあるか
+    tail: Arc<Mutex<bool>>,
 }
 
 impl PLECo {
     pub fn new() -> Self {
-        let mut obj = Self {
+        let obj = Self {
             buffer: Arc::new(Mutex::new(buffer::ViewBuffer::new("tmp.txt"))),
             vars: Arc::new(Mutex::new(HashMap::new())),
+            consts: Arc::new(Mutex::new(HashSet::new())),
+            frames: Arc::new(Mutex::new(Vec::new())),
+            flow: Arc::new(Mutex::new(Flow::Normal)),
+            calls: Arc::new(Mutex::new(Vec::new())),
+            tail: Arc::new(Mutex::new(false)),
         };
 
         {
             if let Some(home_dir) = std::env::home_dir() {
                 obj.vars.lock().unwrap().insert(String::from("HOME"), lexer::Token::String(home_dir.to_str().unwrap().to_string()));
+                obj.consts.lock().unwrap().insert(String::from("HOME"));
             }
         }
 
@@ -44,12 +98,23 @@ impl PLECo {
                 println!("?");
                 continue;
             }
-            self.handle_command(command.trim());
+            self.execute(command.trim());
+        }
+    }
+
+    // トップレベルでコードを実行する。ループの外に残った break / continue はエラーにする
+    pub fn execute(&self, command: &str) {
+        self.handle_command(command);
+
+        let flow = self.flow.lock().unwrap().clone();
+        if flow != Flow::Normal {
+            stray_flow_error(&flow);
         }
     }
 
     pub fn handle_command(&self, command: &str) {
         let commands = lexer::Lexer::new(command).tokenize();
+        let tail = std::mem::take(&mut *self.tail.lock().unwrap());
 
         #[cfg(debug_assertions)]
         println!("{:?}", commands);
@@ -57,6 +122,11 @@ impl PLECo {
         let mut pc = 0;
 
         while pc < commands.len() {
+            // break / continue / ^RT^ が起きたら残りのコマンドは実行しない
+            if *self.flow.lock().unwrap() != Flow::Normal {
+                break;
+            }
+
             if let Some(com) = commands.get(pc) {
                 match com {
                     lexer::Token::Command('a') => pc += self.cmd_insert(pc, &commands),
@@ -65,9 +135,16 @@ impl PLECo {
                     lexer::Token::Command('r') => self.buffer.lock().unwrap().remove_char(),
                     lexer::Token::Command('R') => {
                         let mut buffer = self.buffer.lock().unwrap();
+                        buffer.snapshot();
                         buffer.buffer.clear();
                         buffer.cursor = 0;
+                        buffer.mark = None;
                     }
+                    lexer::Token::Command('m') => {
+                        let mut buffer = self.buffer.lock().unwrap();
+                        buffer.mark = Some(buffer.cursor);
+                    }
+                    lexer::Token::Command('u') => self.buffer.lock().unwrap().undo(),
                     lexer::Token::Command('v') => println!("{}", self.buffer.lock().unwrap().buffer),
                     lexer::Token::Command('q') => process::exit(0),
                     lexer::Token::Command('#') => break,
@@ -77,16 +154,16 @@ impl PLECo {
                     lexer::Token::Command('!') => pc += self.cmd_set_filename(pc, &commands),
                     lexer::Token::Command('S') => pc += self.cmd_save_file(pc, &commands),
                     lexer::Token::Command('x') => pc += self.cmd_load_file(pc, &commands),
-                    lexer::Token::Command('=') => pc += self.cmd_equal(pc, &commands),
+                    lexer::Token::Command('=') => pc += self.cmd_equal(pc, &commands, tail),
                     // lexer::Token::Command('>') => pc += self.cmd_lessthan(pc, &commands),
                     // lexer::Token::Command('<') => pc += self.cmd_morethan(pc, &commands),
-                    lexer::Token::Command('M') => pc += self.cmd_execute_func(pc, &commands),
+                    lexer::Token::Command('M') => pc += self.cmd_execute_func(pc, &commands, tail),
                     // lexer::Token::Command('J') => pc += self.cmd_add_str(pc, &commands),
                     lexer::Token::Command('l') => pc += self.cmd_import(pc, &commands),
-                    lexer::Token::MultiLengthCommand(cmd) => pc += self.handle_multicommand(pc, &commands, cmd),
+                    lexer::Token::MultiLengthCommand(cmd) => pc += self.handle_multicommand(pc, &commands, cmd, tail),
                     lexer::Token::Command(c) => {
-                        if let Some(lexer::Token::Code(func_code)) = self.vars.lock().unwrap().get(&format!("{}", c)) {
-                            self.handle_command(&func_code);
+                        if let Some(func @ (lexer::Token::Code(_) | lexer::Token::Func { .. })) = self.lookup_callable(&c.to_string()) {
+                            self.invoke(&c.to_string(), func, Vec::new(), tail && pc + 1 == commands.len());
                         }
                     }
                     _ => {}
@@ -96,127 +173,505 @@ impl PLECo {
         }
     }
 
-    fn handle_multicommand(&self, pc: usize, commands:  &[lexer::Token], cmd: &str) -> usize {
+    fn handle_multicommand(&self, pc: usize, commands:  &[lexer::Token], cmd: &str, tail: bool) -> usize {
+
+        // ^LI:outer^ のように : の後ろはループのラベル
+        let (cmd, label) = match cmd.split_once(':') {
+            Some((cmd, label)) => (cmd, Some(label)),
+            None => (cmd, None),
+        };
 
-        if cmd == "LI" {
-            return self.cmd_loop_inf(pc, commands);
+        // 組み込みのコマンドでなければ、変数に入れた関数やコードを呼び出す
+        if !BUILTIN_COMMANDS.contains(&cmd) {
+            return self.cmd_call_user(pc, commands, cmd, tail);
         }
 
-        if cmd == "Lo" {
-            return self.cmd_loop_count(pc, commands);
+        match cmd {
+            // 繰り返しと分岐
+            "LI" => self.cmd_loop_inf(pc, commands, label),
+            "Lo" => self.cmd_loop_count(pc, commands, label),
+            "WH" => self.cmd_loop_while(pc, commands, label),
+            "FO" => self.cmd_loop_for(pc, commands, label),
+            "FE" => self.cmd_loop_foreach(pc, commands, label),
+            "IF" => self.cmd_if_integer(pc, commands, tail),
+            "EI" => self.cmd_if_chain(pc, commands, tail),
+            "SW" => self.cmd_switch(pc, commands, tail),
+            "BR" => {
+                *self.flow.lock().unwrap() = Flow::Break(label.map(String::from));
+                0
+            }
+            "CN" => {
+                *self.flow.lock().unwrap() = Flow::Continue(label.map(String::from));
+                0
+            }
+
+            // リストとマップ
+            "PU" => self.cmd_push(pc, commands),
+            "PO" => self.cmd_pop(pc, commands),
+            "PT" => self.cmd_put(pc, commands),
+            "DL" => self.cmd_delete_key(pc, commands),
+
+            // 関数と変数
+            "DF" => self.cmd_define_func(pc, commands),
+            "RT" => self.cmd_return(pc, commands),
+            "GL" => self.cmd_define_global(pc, commands),
+            "EV" => self.cmd_eval(pc, commands),
+            "UN" => self.cmd_unset(pc, commands),
+            "CS" => self.cmd_define_const(pc, commands),
+            "LV" => {
+                self.list_vars();
+                0
+            }
+
+            // 単語・文・段落単位の移動と削除
+            "WF" | "WB" | "EF" | "EB" | "SF" | "SB" | "PF" | "PB" => {
+                self.cmd_move(cmd);
+                0
+            }
+            "DW" | "DE" | "DB" => {
+                self.cmd_delete_word(cmd);
+                0
+            }
+
+            // 大文字・小文字の変換
+            "UC" => self.cmd_convert_case(pc, commands, buffer::Case::Upper),
+            "LC" => self.cmd_convert_case(pc, commands, buffer::Case::Lower),
+            "TC" => self.cmd_convert_case(pc, commands, buffer::Case::Title),
+            "SC" => self.cmd_convert_case(pc, commands, buffer::Case::Swap),
+
+            // 行単位の操作
+            "UM" => {
+                self.buffer.lock().unwrap().mark = None;
+                0
+            }
+            "SL" => self.cmd_select_lines(pc, commands),
+            "SO" => self.cmd_sort_lines(pc, commands),
+            "UQ" => {
+                self.buffer.lock().unwrap().uniq_lines();
+                0
+            }
+            "RV" => {
+                self.buffer.lock().unwrap().reverse_lines();
+                0
+            }
+            "SH" => self.cmd_shuffle_lines(pc, commands),
+
+            // 字下げと空白の整理
+            ">" => {
+                let indent = " ".repeat(self.setting_integer("INDENTWIDTH", 4).max(0) as usize);
+                self.buffer.lock().unwrap().indent_lines(&indent);
+                0
+            }
+            "<" => {
+                let width = self.setting_integer("INDENTWIDTH", 4).max(0) as usize;
+                let tab_width = self.setting_integer("TABWIDTH", 8).max(1) as usize;
+                self.buffer.lock().unwrap().dedent_lines(width, tab_width);
+                0
+            }
+            "ET" => {
+                let tab_width = self.setting_integer("TABWIDTH", 8).max(1) as usize;
+                self.buffer.lock().unwrap().expand_tabs(tab_width);
+                0
+            }
+            "UT" => {
+                let tab_width = self.setting_integer("TABWIDTH", 8).max(1) as usize;
+                self.buffer.lock().unwrap().unexpand_tabs(tab_width);
+                0
+            }
+            "ST" => {
+                self.buffer.lock().unwrap().strip_trailing_whitespace();
+                0
+            }
+            "CB" => {
+                self.buffer.lock().unwrap().collapse_blank_lines();
+                0
+            }
+            "FL" => {
+                self.cmd_fill();
+                0
+            }
+
+            _ => unreachable!("^{}^ is in BUILTIN_COMMANDS but has no handler", cmd),
         }
 
-        if cmd == "IF" {
-            return self.cmd_if_integer(pc, commands);
+    }
+
+    // 単語・文・段落単位でカーソルを動かす
+    fn cmd_move(&self, cmd: &str) {
+        let word_chars = self.setting_string("WORDCHARS", "_");
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.cursor = match cmd {
+            "WF" => buffer.word_forward(&word_chars),
+            "WB" => buffer.word_backward(&word_chars),
+            "EF" => buffer.word_end_forward(&word_chars),
+            "EB" => buffer.word_end_backward(&word_chars),
+            "SF" => buffer.sentence_forward(),
+            "SB" => buffer.sentence_backward(),
+            "PF" => buffer.paragraph_forward(),
+            _ => buffer.paragraph_backward(),
+        };
+    }
 
-        } 
+    // 単語単位で削除する
+    fn cmd_delete_word(&self, cmd: &str) {
+        let word_chars = self.setting_string("WORDCHARS", "_");
+        let mut buffer = self.buffer.lock().unwrap();
+        let cursor = buffer.cursor;
+        let (start, end) = match cmd {
+            "DW" => (cursor, buffer.word_forward(&word_chars)),
+            "DE" => (cursor, buffer.word_end_forward(&word_chars)),
+            _ => (buffer.word_backward(&word_chars), cursor),
+        };
+        if start < end {
+            buffer.replace_range(start, end, "");
+        }
+    }
+
+    // 段落を FILLCOLUMN 桁で詰め直す (マークがあれば選択範囲の行)
+    fn cmd_fill(&self) {
+        let width = self.setting_integer("FILLCOLUMN", 72).max(1) as usize;
+        let mut buffer = self.buffer.lock().unwrap();
+        if let Some(range) = buffer.region_lines().or_else(|| buffer.paragraph_range()) {
+            buffer.fill_lines(range, width);
+        }
+    }
 
-        0
 
+    // ループ本体を 1 回実行する。ループを抜けるなら false
+    // 自分宛て (ラベルなし、またはラベルが一致) の break / continue はここで消費する
+    fn run_loop_body(&self, code: &str, label: Option<&str>) -> bool {
+        self.handle_command(code);
+
+        let mut flow = self.flow.lock().unwrap();
+        match &*flow {
+            Flow::Normal => true,
+            Flow::Break(target) => {
+                if target.is_none() || target.as_deref() == label {
+                    *flow = Flow::Normal;
+                }
+                false
+            }
+            Flow::Continue(target) => {
+                if target.is_none() || target.as_deref() == label {
+                    *flow = Flow::Normal;
+                    true
+                } else {
+                    false
+                }
+            }
+            Flow::Return(_) | Flow::TailCall { .. } => false,
+        }
     }
 
+    // 分岐したコードを実行する。分岐のコマンドが末尾にあれば、分岐先も末尾として扱う
+    fn run_branch(&self, code: &str, tail: bool) {
+        *self.tail.lock().unwrap() = tail;
+        self.handle_command(code);
+    }
 
-    fn cmd_loop_inf(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+    fn cmd_loop_inf(&self, pc: usize, commands:  &[lexer::Token], label: Option<&str>) -> usize {
         if let Some(lexer::Token::Code(code)) = commands.get(pc+1) {
-            loop {
-                self.handle_command(code);
-            }
-            
+            while self.run_loop_body(code, label) {}
             return 1
-
         }
         0
     }   
 
-    fn cmd_if_integer(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+    fn cmd_if_integer(&self, pc: usize, commands:  &[lexer::Token], tail: bool) -> usize {
         if let (Some(expr), Some(lexer::Token::Code(true_code)), Some(lexer::Token::Code(false_code))) = (commands.get(pc+1), commands.get(pc+2), commands.get(pc+3)) {
+            let tail = tail && pc + 4 == commands.len();
 
-            let mut param_ = 0;
-            if let lexer::Token::Integer(param) = expr {
-                param_ = *param;
+            if self.eval_condition(expr) {
+                self.run_branch(true_code, tail);
+            } else {
+                self.run_branch(false_code, tail);
             }
 
-            if let lexer::Token::Var(param) = expr {
-                if let Some(param1) = self.vars.lock().unwrap().get(param) {
-                    if let lexer::Token::Integer(param) = param1 {
-                        param_ = *param;
+            return 3
+        }
+        0
+    }    
+
+    // else-if の連鎖 (^EI^ 条件1 {...} 条件2 {...} ... {それ以外})
+    // 最初に正になった条件のコードだけを実行する。最後の {それ以外} は省略できる
+    fn cmd_if_chain(&self, pc: usize, commands:  &[lexer::Token], tail: bool) -> usize {
+        let (cases, default, consumed) = self.parse_cases(pc, commands);
+        let tail = tail && pc + consumed + 1 == commands.len();
+
+        match cases.into_iter().find(|(cond, _)| self.eval_condition(cond)) {
+            Some((_, code)) => self.run_branch(code, tail),
+            None => if let Some(code) = default {
+                self.run_branch(code, tail);
+            },
+        }
+
+        consumed
+    }
+
+    // 値による分岐 (^SW^ 値 候補1 {...} 候補2 {...} ... {それ以外})
+    // 数値でも文字列でも、評価した値が等しい候補のコードを実行する
+    fn cmd_switch(&self, pc: usize, commands:  &[lexer::Token], tail: bool) -> usize {
+        if let Some(value) = commands.get(pc+1) {
+            let value = self.eval_operand(value);
+            let (cases, default, consumed) = self.parse_cases(pc+1, commands);
+            let tail = tail && pc + consumed + 2 == commands.len();
+
+            match cases.into_iter().find(|(case, _)| expr::equals(&self.eval_operand(case), &value)) {
+                Some((_, code)) => self.run_branch(code, tail),
+                None => if let Some(code) = default {
+                    self.run_branch(code, tail);
+                },
+            }
+
+            return consumed + 1
+        }
+        0
+    }
+
+    // pc の次から続く (値, {コード}) の組と、最後の {コード} を読む
+    // 戻り値は (組の一覧, 最後のコード, 読んだトークン数)
+    fn parse_cases<'a>(&self, pc: usize, commands: &'a [lexer::Token]) -> (Vec<(&'a lexer::Token, &'a str)>, Option<&'a str>, usize) {
+        let mut cases = Vec::new();
+        let mut i = pc + 1;
+
+        loop {
+            match (commands.get(i), commands.get(i+1)) {
+                (Some(lexer::Token::Code(code)), _) => return (cases, Some(code), i - pc),
+                (Some(value @ (lexer::Token::Integer(_) | lexer::Token::Float(_) | lexer::Token::String(_) | lexer::Token::Interp(_) | lexer::Token::Var(_) | lexer::Token::Expr(_) | lexer::Token::Invalid(_))), Some(lexer::Token::Code(code))) => {
+                    cases.push((value, code.as_str()));
+                    i += 2;
+                }
+                _ => return (cases, None, i - pc - 1),
+            }
+        }
+    }
+
+    // リストかマップの要素を順に変数に入れて繰り返す (^FE^$x$ リスト {...})
+    fn cmd_loop_foreach(&self, pc: usize, commands:  &[lexer::Token], label: Option<&str>) -> usize {
+        if let (Some(lexer::Token::Var(varname)), Some(list), Some(lexer::Token::Code(code))) = (commands.get(pc+1), commands.get(pc+2), commands.get(pc+3)) {
+            // マップはキーを挿入順に回る
+            let items = match self.eval_operand(list) {
+                lexer::Token::List(items) => items,
+                lexer::Token::Map(entries) => entries.into_iter().map(|(key, _)| lexer::Token::String(key)).collect(),
+                value => error(&format!("type mismatch: cannot iterate over {:?}", value)),
+            };
+
+            for item in items {
+                self.set_var(varname, item);
+                if !self.run_loop_body(code, label) {
+                    break;
+                }
+            }
+            return 3
+        }
+        0
+    }
+
+    // リストの変数の末尾に値を追加する (^PU^$xs$ 値)
+    fn cmd_push(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let (Some(lexer::Token::Var(varname)), Some(value)) = (commands.get(pc+1), commands.get(pc+2)) {
+            let value = self.eval_operand(value);
+            self.with_var_mut(varname, |target| match target {
+                lexer::Token::List(items) => items.push(value),
+                _ => error(&format!("type mismatch: ${}$ is not a list", varname)),
+            });
+            return 2
+        }
+        0
+    }
+
+    // リストの変数の末尾を取り出して別の変数に入れる (^PO^$xs$ $x$)
+    fn cmd_pop(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let (Some(lexer::Token::Var(varname)), Some(lexer::Token::Var(dest))) = (commands.get(pc+1), commands.get(pc+2)) {
+            let value = self.with_var_mut(varname, |target| match target {
+                lexer::Token::List(items) => items.pop().unwrap_or_else(|| error(&format!("pop from an empty list: ${}$", varname))),
+                _ => error(&format!("type mismatch: ${}$ is not a list", varname)),
+            });
+            self.set_var(dest, value);
+            return 2
+        }
+        0
+    }
+
+    // マップのキー (リストなら添字) に値を入れる (^PT^$m$ キー 値)
+    fn cmd_put(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let (Some(lexer::Token::Var(varname)), Some(key), Some(value)) = (commands.get(pc+1), commands.get(pc+2), commands.get(pc+3)) {
+            let key = self.eval_operand(key);
+            let value = self.eval_operand(value);
+
+            self.with_var_mut(varname, |target| match (&mut *target, &key) {
+                (lexer::Token::List(items), lexer::Token::Integer(i)) => match expr::position(items.len(), *i) {
+                    Ok(position) => items[position] = value,
+                    Err(message) => error(&message),
+                },
+                (lexer::Token::Map(_), _) => match expr::map_set(target.clone(), &key, value) {
+                    Ok(map) => *target = map,
+                    Err(message) => error(&message),
+                },
+                _ => error(&format!("type mismatch: cannot put {:?} into ${}$", key, varname)),
+            });
+            return 3
+        }
+        0
+    }
+
+    // マップのキー (リストなら添字) を消す (^DL^$m$ キー)
+    fn cmd_delete_key(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let (Some(lexer::Token::Var(varname)), Some(key)) = (commands.get(pc+1), commands.get(pc+2)) {
+            let key = self.eval_operand(key);
+
+            self.with_var_mut(varname, |target| match (&mut *target, &key) {
+                (lexer::Token::List(items), lexer::Token::Integer(i)) => match expr::position(items.len(), *i) {
+                    Ok(position) => {
+                        items.remove(position);
                     }
+                    Err(message) => error(&message),
+                },
+                (lexer::Token::Map(_), _) => match expr::map_delete(target.clone(), &key) {
+                    Ok(map) => *target = map,
+                    Err(message) => error(&message),
+                },
+                _ => error(&format!("type mismatch: cannot delete {:?} from ${}$", key, varname)),
+            });
+            return 2
+        }
+        0
+    }
+
+    // 条件が正の間、繰り返す
+    fn cmd_loop_while(&self, pc: usize, commands:  &[lexer::Token], label: Option<&str>) -> usize {
+        if let (Some(expr), Some(lexer::Token::Code(code))) = (commands.get(pc+1), commands.get(pc+2)) {
+            while self.eval_condition(expr) {
+                if !self.run_loop_body(code, label) {
+                    break;
                 }
             }
+            return 2
+        }
+        0
+    }
 
+    // 変数に番号を入れながら繰り返す (^FO^$i$ 開始 終了 増分 {...})
+    // 終了の値は含まない。増分が負なら数を減らしながら繰り返す
+    fn cmd_loop_for(&self, pc: usize, commands:  &[lexer::Token], label: Option<&str>) -> usize {
+        if let (Some(lexer::Token::Var(varname)), Some(start), Some(end), Some(step), Some(lexer::Token::Code(code))) = (commands.get(pc+1), commands.get(pc+2), commands.get(pc+3), commands.get(pc+4), commands.get(pc+5)) {
+            let start = self.eval_integer(start);
+            let end = self.eval_integer(end);
+            let step = self.eval_integer(step);
 
-            if let lexer::Token::Expr(param) = expr {
-                if let Some(lexer::Token::Integer(param)) = self.process_expr(param) {
-                    param_ = param
-                } else {
-                    error("formula eval error");
+            if step == 0 {
+                error("loop step must not be zero");
+            }
+
+            let mut i = start;
+            while (step > 0 && i < end) || (step < 0 && i > end) {
+                self.set_var(varname, lexer::Token::Integer(i));
+                if !self.run_loop_body(code, label) {
+                    break;
                 }
+                i = match i.checked_add(step) {
+                    Some(next) => next,
+                    None => break,
+                };
             }
+            return 5
+        }
+        0
+    }
 
-            if param_ > 0 {
-                self.handle_command(true_code);
+    fn cmd_loop_count(&self, pc: usize, commands:  &[lexer::Token], label: Option<&str>) -> usize {
+        if let (Some(count), Some(lexer::Token::Code(code))) = (commands.get(pc+1), commands.get(pc+2)) {
+            for _ in 0..self.eval_integer(count) {
+                if !self.run_loop_body(code, label) {
+                    break;
+                }
+            }
+            return 2;
+        }
+        0
+    }    
+
+    // 行を選択する (開始行と終了行, 1 始まり)
+    fn cmd_select_lines(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let (Some(from), Some(to)) = (commands.get(pc+1), commands.get(pc+2)) {
+            let from = self.eval_integer(from);
+            let to = self.eval_integer(to);
+            let mut buffer = self.buffer.lock().unwrap();
+
+            if let (Some(start), Some(end)) = (buffer.line_offset(from.max(0) as usize), buffer.line_offset(to.max(0) as usize)) {
+                buffer.mark = Some(start);
+                buffer.cursor = end;
             } else {
-                self.handle_command(false_code);
+                error("line out of range");
             }
 
             return 2
         }
         0
-    }    
+    }
 
-    fn cmd_loop_count(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
-        if let (Some(lexer::Token::Integer(count)), Some(lexer::Token::Code(code))) = (commands.get(pc+1), commands.get(pc+2)) {
-            for _ in 0..*count {
-                self.handle_command(code);
+    // 行を並べ替える
+    // オプション: n = 数値順, i = 大文字小文字を区別しない, r = 逆順, 数字 = 何番目のフィールドで比較するか
+    fn cmd_sort_lines(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let Some(options) = commands.get(pc+1) {
+            let options = self.eval_string(options);
+            self.buffer.lock().unwrap().sort_lines(&options);
+            return 1
+        }
+        0
+    }
+
+    // 行をシャッフルする (引数はシード)
+    fn cmd_shuffle_lines(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let Some(seed) = commands.get(pc+1) {
+            let seed = self.eval_integer(seed);
+            self.buffer.lock().unwrap().shuffle_lines(seed as u64);
+            return 1
+        }
+        0
+    }
+
+    // 大文字・小文字を変換する
+    // 範囲: c = カーソル位置の文字, w = 単語, r = 選択範囲, b = バッファー全体
+    fn cmd_convert_case(&self, pc: usize, commands:  &[lexer::Token], case: buffer::Case) -> usize {
+        if let Some(scope) = commands.get(pc+1) {
+            let scope = self.eval_string(scope);
+            let word_chars = self.setting_string("WORDCHARS", "_");
+            let mut buffer = self.buffer.lock().unwrap();
+
+            let (start, end) = match scope.as_str() {
+                "c" => buffer.char_range(),
+                "w" => buffer.word_range(&word_chars),
+                "r" => buffer.region().unwrap_or_else(|| error("mark is not set")),
+                "b" => (0, buffer.buffer.len()),
+                _ => error("unknown scope"),
+            };
+            buffer.convert_case(start, end, case);
+
+            // 1 文字だけ変換したときは次の文字へ進む
+            if scope == "c" {
+                buffer.cur_move_right();
             }
-            return 2;
+
+            return 1
         }
         0
-    }    
+    }
 
     // バッファーにテキストを追加します
     fn cmd_insert(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
 
-        // 変数である場合と文字列リテラルである場合で処理を分けます
+        // 引数は文字列, 数値, 変数, 数式のどれでもよい
 
         if let Some(args1_token) = commands.get(pc+1) {
             #[cfg(debug_assertions)]
             println!("{:?}", args1_token);
 
-            match args1_token.clone() {
-                // 文字列リテラルである場合
-                lexer::Token::String(string) => {
-                    for c in string.chars() {
-                        self.buffer.lock().unwrap().add_char(c);
-                    }
-                },
-                // 変数である場合
-                lexer::Token::Var(varname) => {
-                    let vars = self.vars.lock().unwrap();
-
-                    if let Some(value) = vars.get(&varname) {
-                        if let lexer::Token::String(string) = value {
-                            for c in string.chars() {
-                                self.buffer.lock().unwrap().add_char(c);
-                            }
-                        } else if let lexer::Token::Integer(value) = value {
-                            for c in value.to_string().chars() {
-                                self.buffer.lock().unwrap().add_char(c);
-                            }
-                        } else {
-                            error("type mismatch");
-                        }
-                    } else {
-                        error("variables do not exist");
-                    }
-                },
-                lexer::Token::Integer(value) => {
-                    let string = value.to_string();
-                    for c in string.chars() {
-                        self.buffer.lock().unwrap().add_char(c);
-                    }
-                }
-                _ => { error("type mismatch"); }
+            let string = self.eval_display(args1_token);
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.snapshot();
+            for c in string.chars() {
+                buffer.add_char(c);
             }
             return 1;
 
@@ -229,32 +684,12 @@ impl PLECo {
     // カーソルを指定の位置に移動させる
     fn cmd_jump_cur(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
 
-        // 変数である場合と数字リテラルである場合で処理を分けます
-
         if let Some(args1_token) = commands.get(pc+1) {
+            let to = self.eval_integer(args1_token);
             let mut buffer = self.buffer.lock().unwrap();
-            match args1_token.clone() {
-                // 数字リテラルである場合
-                lexer::Token::Integer(to) => if to >= 0 && to < buffer.buffer.len() as i32 {
-                    buffer.cursor = to as usize
-                },
-                // 変数である場合
-                lexer::Token::Var(varname) => {
-                    let vars = self.vars.lock().unwrap();
-
-                    if let Some(value) = vars.get(&varname) {
-                        if let lexer::Token::Integer(to) = value {
-                            if *to >= 0 && *to < buffer.buffer.len() as i32 {
-                                buffer.cursor = *to as usize
-                            }
-                        } else {
-                            error("type mismatch");
-                        }
-                    } else {
-                        error("variables do not exist");
-                    }
-                },
-                _ => { error("type mismatch"); }
+            // 文字の途中を指したときはその文字の先頭に合わせる
+            if to >= 0 && to < buffer.buffer.len() as i64 {
+                buffer.cursor = buffer.clamp(to as usize)
             }
             return 1;
 
@@ -266,34 +701,12 @@ impl PLECo {
 
     // 検索をかける
     fn cmd_search(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
-        // 変数である場合と文字列リテラルである場合で処理を分けます
-        let mut buffer = self.buffer.lock().unwrap();
 
         if let Some(args1_token) = commands.get(pc+1) {
-            match args1_token.clone() {
-                // 文字列リテラルである場合
-                lexer::Token::String(string) => {
-                    if let Some(pos) = buffer.buffer.find(&string) {
-                        buffer.cursor = pos;
-                    }
-                },
-                // 変数である場合
-                lexer::Token::Var(varname) => {
-                    let vars = self.vars.lock().unwrap();
-
-                    if let Some(value) = vars.get(&varname) {
-                        if let lexer::Token::String(string) = value {
-                            if let Some(pos) = buffer.buffer.find(string) {
-                                buffer.cursor = pos;
-                            }
-                        } else {
-                            error("type mismatch");
-                        }
-                    } else {
-                        error("variables do not exist");
-                    }
-                },
-                _ => { error("type mismatch"); }
+            let string = self.eval_string(args1_token);
+            let mut buffer = self.buffer.lock().unwrap();
+            if let Some(pos) = buffer.buffer.find(&string) {
+                buffer.cursor = pos;
             }
             return 1;
 
@@ -306,69 +719,293 @@ impl PLECo {
     // 変数を定義
     fn cmd_define(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
         // 変数を定義します
-        let mut buffer = self.buffer.lock().unwrap();
-
-        let mut vars = self.vars.lock().unwrap();
 
         if let (Some(args1_token), Some(args2_token)) = (commands.get(pc+1), commands.get(pc+2)) {
 
-            let mut token_ = args2_token.clone();
-
             if let lexer::Token::Var(varname) = args1_token {
+                let value = self.eval_operand(args2_token);
+                self.set_var(varname, value);
+            }
 
-                if let lexer::Token::Var(var) = args2_token {
-                    if let Some(token) = vars.get(var) {
-                        token_ = token.clone();
-                    }
-                }
+            return 2;
+        }
 
-                if let lexer::Token::Expr(expr) = args2_token {
-                    
-                    if let Some(result) = self.process_expr(expr) {
-                        token_ = result
-                    } else {
-                        error("formula eval error");
-                    }
+        0
 
-                }
+    }
+
+    // 関数の中からでもグローバル変数を定義する (^GL^$x$ 値)
+    fn cmd_define_global(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let (Some(lexer::Token::Var(varname)), Some(value)) = (commands.get(pc+1), commands.get(pc+2)) {
+            let value = self.eval_operand(value);
+            self.check_assignable(varname);
+            self.vars.lock().unwrap().insert(varname.clone(), value);
+            return 2
+        }
+        0
+    }
 
-                vars.insert(varname.clone(), token_);
+    // 定数を定義する (^CS^$NAME$ 値)。定数は @ や ^UN^ で書き換えられない
+    fn cmd_define_const(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let (Some(lexer::Token::Var(varname)), Some(value)) = (commands.get(pc+1), commands.get(pc+2)) {
+            let value = self.eval_operand(value);
+            self.check_assignable(varname);
+            self.vars.lock().unwrap().insert(varname.clone(), value);
+            self.consts.lock().unwrap().insert(varname.clone());
+            return 2
+        }
+        0
+    }
+
+    // 変数を消す (^UN^$x$)。関数の中ならローカル変数を先に消す。ない変数は何もしない
+    fn cmd_unset(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let Some(lexer::Token::Var(varname)) = commands.get(pc+1) {
+            if let Some(frame) = self.frames.lock().unwrap().last_mut() {
+                if frame.vars.remove(varname).is_some() {
+                    return 1
+                }
             }
+            self.check_not_const(varname);
+            self.vars.lock().unwrap().remove(varname);
+            return 1
+        }
+        0
+    }
 
-            return 2;
+    // 変数を一覧表示する (^LV^)。名前、型、値の順で、ローカル変数には (local) を付ける
+    fn list_vars(&self) {
+        let mut entries: Vec<(String, lexer::Token, &str)> = Vec::new();
+        if let Some(frame) = self.frames.lock().unwrap().last() {
+            entries.extend(frame.vars.iter().map(|(name, value)| (name.clone(), value.clone(), " (local)")));
+        }
+        let consts = self.consts.lock().unwrap().clone();
+        entries.extend(self.vars.lock().unwrap().iter().map(|(name, value)| {
+            (name.clone(), value.clone(), if consts.contains(name) { " (const)" } else { "" })
+        }));
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (name, value, note) in entries {
+            let (kind, shown) = match &value {
+                lexer::Token::Integer(_) => ("integer", expr::repr(&value)),
+                lexer::Token::Float(_) => ("float", expr::repr(&value)),
+                lexer::Token::String(_) => ("string", expr::repr(&value)),
+                lexer::Token::List(_) => ("list", expr::repr(&value)),
+                lexer::Token::Map(_) => ("map", expr::repr(&value)),
+                lexer::Token::Code(code) => ("code", Some(format!("{{{}}}", code))),
+                lexer::Token::Func { params, code, .. } => ("function", Some(format!("\"{}\" {{{}}}", params.join(" "), code))),
+                _ => ("other", None),
+            };
+            println!("${}${}\t{}\t{}", name, note, kind, shown.unwrap_or_else(|| format!("{:?}", value)));
         }
+    }
 
+    // 関数を定義する (^DF^$name$ "a b" {...})。引数名は空白区切り
+    // 関数の中で定義すると、その時点のローカル変数を取り込む
+    fn cmd_define_func(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let (Some(lexer::Token::Var(name)), Some(params), Some(lexer::Token::Code(code))) = (commands.get(pc+1), commands.get(pc+2), commands.get(pc+3)) {
+            if is_builtin_function(name) {
+                error(&format!("cannot redefine built-in function: {}", name));
+            }
+            let params: Vec<String> = self.eval_string(params).split_whitespace().map(String::from).collect();
+            self.set_var(name, lexer::Token::Func { params, code: code.clone(), captured: self.capture() });
+            return 3
+        }
         0
+    }
 
+    // 関数から値を返す (^RT^ 値)。値を省略すると 0
+    // ^RT^(f(x)) のように関数を呼ぶだけなら、スタックを伸ばさずに呼び出し元で f を呼ぶ
+    // 関数の中の ^RT^{...} は、ローカル変数を取り込んだクロージャーを返す
+    fn cmd_return(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let Some(lexer::Token::Code(code)) = commands.get(pc+1) {
+            if !self.frames.lock().unwrap().is_empty() {
+                *self.flow.lock().unwrap() = Flow::Return(self.closure(code));
+                return 1
+            }
+        }
+
+        if let Some(lexer::Token::Expr(source)) = commands.get(pc+1) {
+            if let Some(flow) = self.tail_call(source) {
+                *self.flow.lock().unwrap() = flow;
+                return 1
+            }
+        }
+
+        let (value, consumed) = match commands.get(pc+1) {
+            Some(token) if is_operand(token) => (self.eval_operand(token), 1),
+            _ => (lexer::Token::Integer(0), 0),
+        };
+        *self.flow.lock().unwrap() = Flow::Return(value);
+        consumed
+    }
+
+    // ^RT^ の数式が利用者の関数の呼び出しだけなら、その呼び出しを TailCall にする
+    fn tail_call(&self, source: &str) -> Option<Flow> {
+        if self.frames.lock().unwrap().is_empty() {
+            return None;
+        }
+        let Ok(expr::Expr::Call(name, args)) = expr::parse(source) else {
+            return None;
+        };
+        if is_builtin_function(&name) {
+            return None;
+        }
+        let callee @ lexer::Token::Func { .. } = self.lookup_callable(&name)? else {
+            return None;
+        };
+        let args = match args.iter().map(|arg| expr::eval(arg, self)).collect::<Result<Vec<_>, _>>() {
+            Ok(args) => args,
+            Err(message) => error(&message),
+        };
+        Some(Flow::TailCall { name, callee, args, returning: true })
+    }
+
+    // ^name^ で関数やコードを呼び出す。関数なら引数の数だけ後ろの引数を読み、戻り値は捨てる
+    fn cmd_call_user(&self, pc: usize, commands:  &[lexer::Token], name: &str, tail: bool) -> usize {
+        let callee = self.lookup_callable(name);
+        let arity = match &callee {
+            Some(lexer::Token::Func { params, .. }) => params.len(),
+            Some(lexer::Token::Code(_)) => 0,
+            _ => error(&format!("unknown command: ^{}^", name)),
+        };
+        let args: Vec<lexer::Token> = commands[pc+1..].iter().take(arity).take_while(|token| is_operand(token)).map(|token| self.eval_operand(token)).collect();
+        let consumed = args.len();
+        if let Some(callee) = callee {
+            self.invoke(name, callee, args, tail && pc + consumed + 1 == commands.len());
+        }
+        consumed
+    }
+
+    // マクロ (コード) や関数を呼び出す。末尾なら TailCall にして呼び出し元に任せる
+    fn invoke(&self, name: &str, callee: lexer::Token, args: Vec<lexer::Token>, tail: bool) {
+        if tail {
+            *self.flow.lock().unwrap() = Flow::TailCall { name: name.to_string(), callee, args, returning: false };
+            return;
+        }
+        match callee {
+            lexer::Token::Code(code) => self.run_macro(name, &code),
+            func => if let Err(message) = self.call_function(name, &func, args) {
+                error(&message);
+            },
+        }
+    }
+
+    // 呼び出しの深さを数える。MAXDEPTH を超えたら呼び出しの連なりを表示して止める
+    fn enter_call(&self, name: &str) {
+        // スタックの大きさ (STACK_SIZE) で足りる深さまでしか許さない
+        let max_depth = self.setting_integer("MAXDEPTH", 1000);
+        if !(1..=MAX_DEPTH_LIMIT).contains(&max_depth) {
+            error(&format!("MAXDEPTH must be between 1 and {}, found {}", MAX_DEPTH_LIMIT, max_depth));
+        }
+        let max_depth = max_depth as usize;
+        let mut calls = self.calls.lock().unwrap();
+        if calls.len() >= max_depth {
+            // 同じ名前の連続は f (x998) のようにまとめる
+            let mut chain: Vec<(&str, usize)> = Vec::new();
+            for call in calls.iter().map(String::as_str).chain([name]) {
+                match chain.last_mut() {
+                    Some((last, count)) if *last == call => *count += 1,
+                    _ => chain.push((call, 1)),
+                }
+            }
+            let mut chain: Vec<String> = chain.iter().map(|(call, count)| if *count > 1 { format!("{} (x{})", call, count) } else { call.to_string() }).collect();
+            // 長すぎるときは最初と最後だけ表示する
+            if chain.len() > 10 {
+                let omitted = chain.len() - 10;
+                chain.splice(5..chain.len() - 5, [format!("... ({} more)", omitted)]);
+            }
+            error(&format!("maximum call depth exceeded (MAXDEPTH = {})\n\tcall chain: {}", max_depth, chain.join(" -> ")));
+        }
+        calls.push(name.to_string());
+    }
+
+    fn leave_call(&self) {
+        self.calls.lock().unwrap().pop();
+    }
+
+    // マクロを今のスコープで実行する。末尾でのマクロ呼び出しはここで続けて実行する
+    fn run_macro(&self, name: &str, code: &str) {
+        let (mut name, mut code) = (name.to_string(), code.to_string());
+        loop {
+            self.enter_call(&name);
+            *self.tail.lock().unwrap() = true;
+            self.handle_command(&code);
+            self.leave_call();
+
+            let flow = self.flow.lock().unwrap().clone();
+            match flow {
+                Flow::TailCall { name: next, callee: lexer::Token::Code(next_code), returning: false, .. } => {
+                    *self.flow.lock().unwrap() = Flow::Normal;
+                    (name, code) = (next, next_code);
+                }
+                // 末尾での関数の呼び出しは、マクロの戻り値がないのでここで普通に呼ぶ
+                Flow::TailCall { name: next, callee, args, returning: false } => {
+                    *self.flow.lock().unwrap() = Flow::Normal;
+                    if let Err(message) = self.call_function(&next, &callee, args) {
+                        error(&message);
+                    }
+                    return;
+                }
+                // ^RT^ や break はそのまま外側に伝える
+                _ => return,
+            }
+        }
+    }
+
+    // 関数を呼び出す。引数はローカル変数になり、^RT^ がなければ 0 を返す
+    // 新しいスコープは取り込んだ変数に引数を重ねて作る
+    // 末尾での呼び出し (TailCall) はスタックを伸ばさずにここで続けて実行する
+    fn call_function(&self, name: &str, func: &lexer::Token, args: Vec<lexer::Token>) -> Result<lexer::Token, String> {
+        let (mut name, mut func, mut args) = (name.to_string(), func.clone(), args);
+        // ^RT^ 以外での末尾呼び出しがあれば、最初の関数の戻り値は 0 になる
+        let mut discard = false;
+
+        loop {
+            let lexer::Token::Func { params, code, captured } = &func else {
+                return Err(format!("{} is not a function", name));
+            };
+            if params.len() != args.len() {
+                return Err(format!("{} expects {} arguments, found {}", name, params.len(), args.len()));
+            }
+
+            let mut vars: HashMap<String, lexer::Token> = captured.iter().cloned().collect();
+            vars.extend(params.iter().cloned().zip(args));
+
+            self.frames.lock().unwrap().push(Frame { vars, name: name.clone(), callee: func.clone() });
+            self.enter_call(&name);
+            *self.tail.lock().unwrap() = true;
+            self.handle_command(code);
+
+            // 末尾のマクロ呼び出しは関数のスコープのまま実行する
+            let flow = self.flow.lock().unwrap().clone();
+            if let Flow::TailCall { name: next, callee: lexer::Token::Code(next_code), returning: false, .. } = flow {
+                *self.flow.lock().unwrap() = Flow::Normal;
+                self.run_macro(&next, &next_code);
+            }
+
+            self.leave_call();
+            self.frames.lock().unwrap().pop();
+
+            // break / continue は関数の外のループまでは届かない
+            let flow = std::mem::replace(&mut *self.flow.lock().unwrap(), Flow::Normal);
+            match flow {
+                Flow::Normal => return Ok(lexer::Token::Integer(0)),
+                Flow::Return(value) => return Ok(if discard { lexer::Token::Integer(0) } else { value }),
+                Flow::TailCall { name: next, callee, args: next_args, returning } => {
+                    discard |= !returning;
+                    (name, func, args) = (next, callee, next_args);
+                }
+                flow => stray_flow_error(&flow),
+            }
+        }
     }
 
     // ファイル名を定義する
     fn cmd_set_filename(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
-        // 変数である場合と文字列リテラルである場合で処理を分けます
-        let mut buffer = self.buffer.lock().unwrap();
 
         if let Some(args1_token) = commands.get(pc+1) {
-            match args1_token.clone() {
-                // 文字列リテラルである場合
-                lexer::Token::String(string) => {
-                    buffer.filename = string;
-                },
-                // 変数である場合
-                lexer::Token::Var(varname) => {
-                    let vars = self.vars.lock().unwrap();
-
-                    if let Some(value) = vars.get(&varname) {
-                        if let lexer::Token::String(string) = value {
-                            buffer.filename = string.clone()
-                        } else {
-                            error("type mismatch");
-                        }
-                    } else {
-                        error("variables do not exist");
-                    }
-                },
-                _ => { error("type mismatch"); }
-            }
+            let filename = self.eval_string(args1_token);
+            self.buffer.lock().unwrap().filename = filename;
             return 1;
 
         }
@@ -378,13 +1015,14 @@ impl PLECo {
     }
 
     // ファイルを読み込む
-    fn cmd_load_file(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+    fn cmd_load_file(&self, _pc: usize, _commands:  &[lexer::Token]) -> usize {
         // 変数である場合と文字列リテラルである場合で処理を分けます
         let mut buffer = self.buffer.lock().unwrap();
 
         match fs::File::open(&buffer.filename) {
             Ok(mut file) => {
-                file.read_to_string(&mut buffer.buffer);
+                buffer.snapshot();
+                let _ = file.read_to_string(&mut buffer.buffer);
             },
             Err(_) => { error("cannot read the file because it does not exist"); }
         }
@@ -394,13 +1032,18 @@ impl PLECo {
     }
 
     // ファイルを保存する
-    fn cmd_save_file(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
-        // 変数である場合と文字列リテラルである場合で処理を分けます
+    fn cmd_save_file(&self, _pc: usize, _commands:  &[lexer::Token]) -> usize {
+        // FINALNEWLINE が正なら末尾の改行をちょうど 1 つにしてから保存します
+        let final_newline = self.setting_integer("FINALNEWLINE", 0) > 0;
         let mut buffer = self.buffer.lock().unwrap();
 
+        if final_newline {
+            buffer.ensure_final_newline();
+        }
+
         match fs::File::create(&buffer.filename) {
             Ok(mut file) => {
-                file.write_all(buffer.buffer.clone().as_bytes());
+                let _ = file.write_all(buffer.buffer.clone().as_bytes());
             },
             Err(_) => { error("file cannot be created."); }
         }
@@ -411,13 +1054,15 @@ impl PLECo {
 
 
     // イコール
-    fn cmd_equal(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+    // 両辺を評価してから比べる (=$a$*1; は $a$ の値と 1 を比べる)
+    fn cmd_equal(&self, pc: usize, commands:  &[lexer::Token], tail: bool) -> usize {
 
         if let (Some(obj1), Some(obj2), Some(lexer::Token::Code(true_code)), Some(lexer::Token::Code(false_code))) = (commands.get(pc+1), commands.get(pc+2), commands.get(pc+3), commands.get(pc+4)) {
-            if obj1 == obj2 {
-                self.handle_command(true_code);
+            let tail = tail && pc + 5 == commands.len();
+            if expr::equals(&self.eval_operand(obj1), &self.eval_operand(obj2)) {
+                self.run_branch(true_code, tail);
             } else {
-                self.handle_command(false_code);
+                self.run_branch(false_code, tail);
             }
 
             return 4
@@ -427,26 +1072,58 @@ impl PLECo {
     }
 
     // マクロ実行
-    fn cmd_execute_func(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+    // コードはその場で実行し、クロージャーは引数なしの関数として呼び出す
+    fn cmd_execute_func(&self, pc: usize, commands:  &[lexer::Token], tail: bool) -> usize {
 
-        if let Some(lexer::Token::Code(code)) = commands.get(pc+1) {
-            self.handle_command(code);
+        if let Some(args1_token) = commands.get(pc+1) {
+            if let lexer::Token::Code(code) = args1_token {
+                self.handle_command(code);
+                return 1
+            }
+            let name = match args1_token {
+                lexer::Token::Var(varname) => varname.as_str(),
+                _ => "<closure>",
+            };
+            match self.eval_operand(args1_token) {
+                callee @ (lexer::Token::Code(_) | lexer::Token::Func { .. }) => self.invoke(name, callee, Vec::new(), tail && pc + 2 == commands.len()),
+                _ => error("type mismatch"),
+            }
             return 1
         }
 
         0
     }
 
+    // 文字列やコードを今のスコープで実行する (^EV^ 値)
+    fn cmd_eval(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
+        if let Some(args1_token) = commands.get(pc+1) {
+            let code = match self.eval_operand(args1_token) {
+                lexer::Token::String(code) | lexer::Token::Code(code) => code,
+                value => error(&format!("type mismatch: cannot eval {:?}", value)),
+            };
+            // 自分自身を ^EV^ し続けることもあるので呼び出しの深さに数える
+            self.enter_call("^EV^");
+            self.handle_command(&code);
+            self.leave_call();
+            return 1
+        }
+        0
+    }
+
     // モジュールを読み込む
     fn cmd_import(&self, pc: usize, commands:  &[lexer::Token]) -> usize {
 
-        if let Some(lexer::Token::String(fname)) = commands.get(pc+1) {
+        if let Some(args1_token) = commands.get(pc+1) {
+            let fname = self.eval_string(args1_token);
             
-            match fs::File::open(fname) {
+            match fs::File::open(&fname) {
                 Ok(mut file) => {
                     let mut buf = String::new();
-                    file.read_to_string(&mut buf);
+                    let _ = file.read_to_string(&mut buf);
+                    // モジュールが自分自身を読み込むこともあるので呼び出しの深さに数える
+                    self.enter_call(&format!("l\"{}\"", fname));
                     self.handle_command(&buf);
+                    self.leave_call();
                 },
                 Err(_) => {
                     error("Module file does not exist");
@@ -462,104 +1139,428 @@ impl PLECo {
 
     // コマンドとは関係ない
 
+    // 引数を評価して値にする。変数は中身を、数式は計算結果を返し、リテラルはそのまま返す
+    // どのコマンドの引数もここを通して評価する
+    fn eval_operand(&self, token: &lexer::Token) -> lexer::Token {
+        match token {
+            lexer::Token::Var(varname) => match self.lookup_var(varname) {
+                Some(value) => value,
+                None => error(&format!("variables do not exist: ${}$", varname)),
+            },
+            lexer::Token::Expr(expr) => match self.process_expr(expr) {
+                Some(value) => value,
+                None => error("formula eval error"),
+            },
+            lexer::Token::Invalid(message) => error(message),
+            lexer::Token::Interp(parts) => match expr::interpolate(parts, self) {
+                Ok(string) => lexer::Token::String(string),
+                Err(message) => error(&message),
+            },
+            _ => token.clone(),
+        }
+    }
+
+    // 変数を読む。関数の中ならローカル変数を先に探す
+    fn lookup_var(&self, name: &str) -> Option<lexer::Token> {
+        if let Some(value) = self.frames.lock().unwrap().last().and_then(|frame| frame.vars.get(name)) {
+            return Some(value.clone());
+        }
+        self.vars.lock().unwrap().get(name).cloned()
+    }
+
+    // 呼び出す関数やマクロを探す。変数になければ、今実行中の関数自身 (再帰呼び出し)
+    fn lookup_callable(&self, name: &str) -> Option<lexer::Token> {
+        self.lookup_var(name).or_else(|| {
+            let frames = self.frames.lock().unwrap();
+            frames.last().filter(|frame| frame.name == name).map(|frame| frame.callee.clone())
+        })
+    }
+
+    // 今のローカル変数を取り込んだ引数なしのクロージャーを作る (^RT^{...} と closure())
+    fn closure(&self, code: &str) -> lexer::Token {
+        lexer::Token::Func { params: Vec::new(), code: code.to_string(), captured: self.capture() }
+    }
+
+    // クロージャーに取り込むため、今のローカル変数を写し取る
+    fn capture(&self) -> Vec<(String, lexer::Token)> {
+        match self.frames.lock().unwrap().last() {
+            Some(frame) => frame.vars.iter().map(|(name, value)| (name.clone(), value.clone())).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // 定数なら書き換えられないのでエラーにする
+    fn check_not_const(&self, name: &str) {
+        if self.consts.lock().unwrap().contains(name) {
+            error(&format!("cannot assign to constant: ${}$", name));
+        }
+    }
+
+    // 新しく名前を付けられるか。^SO^ のように組み込みのコマンドと同じ名前は ^...^ で呼べないので認めない
+    fn check_assignable(&self, name: &str) {
+        if BUILTIN_COMMANDS.contains(&name) {
+            error(&format!("cannot redefine built-in command: ^{}^", name));
+        }
+        self.check_not_const(name);
+    }
+
+    // 変数に書き込む。関数の中ならローカル変数になる
+    fn set_var(&self, name: &str, value: lexer::Token) {
+        self.check_assignable(name);
+        if let Some(frame) = self.frames.lock().unwrap().last_mut() {
+            frame.vars.insert(name.to_string(), value);
+            return;
+        }
+        self.vars.lock().unwrap().insert(name.to_string(), value);
+    }
+
+    // 変数をその場で書き換える (^PU^ や ^PT^)
+    fn with_var_mut<R>(&self, name: &str, f: impl FnOnce(&mut lexer::Token) -> R) -> R {
+        if let Some(value) = self.frames.lock().unwrap().last_mut().and_then(|frame| frame.vars.get_mut(name)) {
+            return f(value);
+        }
+        self.check_not_const(name);
+        match self.vars.lock().unwrap().get_mut(name) {
+            Some(value) => f(value),
+            None => error(&format!("variables do not exist: ${}$", name)),
+        }
+    }
+
+    // 引数を文字列として評価する
+    fn eval_string(&self, token: &lexer::Token) -> String {
+        match self.eval_operand(token) {
+            lexer::Token::String(string) => string,
+            value => error(&format!("type mismatch: expected a string, found {:?}", value)),
+        }
+    }
+
+    // 引数を数値として評価する
+    fn eval_integer(&self, token: &lexer::Token) -> i64 {
+        match self.eval_operand(token) {
+            lexer::Token::Integer(value) => value,
+            value => error(&format!("type mismatch: expected an integer, found {:?}", value)),
+        }
+    }
+
+    // 条件として評価する。正なら真
+    fn eval_condition(&self, token: &lexer::Token) -> bool {
+        match self.eval_operand(token) {
+            lexer::Token::Integer(value) => value > 0,
+            lexer::Token::Float(value) => value > 0.0,
+            value => error(&format!("type mismatch: expected a condition, found {:?}", value)),
+        }
+    }
+
+    // 引数をバッファーに挿入する文字列として評価する
+    fn eval_display(&self, token: &lexer::Token) -> String {
+        match self.eval_operand(token) {
+            // PRECISION が定義されていれば小数点以下をその桁数にする
+            lexer::Token::Float(value) => {
+                let precision = self.setting_integer("PRECISION", -1);
+                expr::format_float(value, usize::try_from(precision).ok())
+            }
+            value => match expr::display(&value) {
+                Some(string) => string,
+                None => error(&format!("type mismatch: cannot insert {:?}", value)),
+            },
+        }
+    }
+
+    // 設定用の変数を読む。未定義なら既定値
+    fn setting_string(&self, name: &str, default: &str) -> String {
+        match self.vars.lock().unwrap().get(name) {
+            Some(lexer::Token::String(value)) => value.clone(),
+            Some(_) => error(&format!("{} must be a string", name)),
+            None => default.to_string(),
+        }
+    }
+
+    fn setting_integer(&self, name: &str, default: i64) -> i64 {
+        match self.vars.lock().unwrap().get(name) {
+            Some(lexer::Token::Integer(value)) => *value,
+            Some(_) => error(&format!("{} must be an integer", name)),
+            None => default,
+        }
+    }
+
     // 数式計算
+    // 中置記法として読めればそのまま評価し、読めなければ前置記法 ((+*3;*5;) や (^CT^"world")) として評価する
 
     fn process_expr(&self, expr: &str) -> Option<lexer::Token> {
 
+        let message = match expr::parse(expr) {
+            Ok(ast) => match expr::eval(&ast, self) {
+                Ok(value) => return Some(value),
+                Err(message) => error(&message),
+            },
+            Err(message) => message,
+        };
+
         let tokens = lexer::Lexer::new(expr).tokenize();
         #[cfg(debug_assertions)]
         println!("{:?}", tokens);
-        if let Some(operation) = tokens.get(0) {
-
-            if *operation == lexer::Token::MultiLengthCommand(String::from("CT")) {
-                if let Some(param) = tokens.get(1) {
 
-                    let mut pat = "";
-                    let vars = self.vars.lock().unwrap();
+        let is_operand = |token: &lexer::Token| matches!(token, lexer::Token::Integer(_) | lexer::Token::Float(_) | lexer::Token::Var(_) | lexer::Token::Expr(_) | lexer::Token::String(_) | lexer::Token::Interp(_) | lexer::Token::Invalid(_));
 
-                    if let lexer::Token::Var(param) = param {
-                        if let Some(param) = vars.get(param) {
-                            if let lexer::Token::String(param) = param {
-                                let pat_ = param;
-                                pat = pat_;
-                            }
-                        }
-                    }
+        match tokens.as_slice() {
+            [lexer::Token::MultiLengthCommand(cmd), param] if cmd == "CT" && is_operand(param) => {
+                let pat = self.eval_string(param);
+                let count = self.buffer.lock().unwrap().buffer.matches(&pat).count();
+                Some(lexer::Token::Integer(count as i64))
+            }
+            [lexer::Token::Command(operation), param1, param2] if is_operand(param1) && is_operand(param2) => {
+                let op = match operation {
+                    '+' => expr::BinOp::Add,
+                    '-' => expr::BinOp::Sub,
+                    'x' => expr::BinOp::Mul,
+                    '/' => expr::BinOp::Div,
+                    '%' => expr::BinOp::Rem,
+                    '=' => expr::BinOp::Eq,
+                    '>' => expr::BinOp::Gt,
+                    '<' => expr::BinOp::Lt,
+                    '!' => expr::BinOp::Ne,
+                    _ => error("unknown operation"),
+                };
+                let param1_ = self.eval_operand(param1);
+                let param2_ = self.eval_operand(param2);
+                match expr::binary(op, param1_, param2_) {
+                    Ok(value) => Some(value),
+                    Err(message) => error(&message),
+                }
+            }
+            _ => error(&message),
+        }
+    }
 
-                    if let lexer::Token::String(param) = param {
-                        pat = param
-                    }
+}
 
-                    let count = self.buffer.lock().unwrap().buffer.matches(pat).count();
-                    return Some(lexer::Token::Integer(count as i32));
-                } 
-            } else {
+// 中置記法の数式から変数やバッファーを読むための実装
+impl expr::Env for PLECo {
+    fn get_var(&self, name: &str) -> Result<lexer::Token, String> {
+        match self.lookup_var(name) {
+            Some(value) => Ok(value),
+            None => Err(format!("variables do not exist: {}", name)),
+        }
+    }
 
+    fn call(&self, name: &str, args: Vec<lexer::Token>) -> Result<lexer::Token, String> {
+        match (name, args.as_slice()) {
+            // バッファー中の出現回数 (^CT^ と同じ)
+            ("count", [lexer::Token::String(pat)]) => {
+                let count = self.buffer.lock().unwrap().buffer.matches(pat.as_str()).count();
+                Ok(lexer::Token::Integer(count as i64))
+            }
 
-                if let (Some(param1), Some(param2)) = (tokens.get(1), tokens.get(2)) {
+            // バッファーの内容を読む。位置は t と同じくバイト単位
+            ("char", []) => {
+                let buffer = self.buffer.lock().unwrap();
+                let (start, end) = buffer.char_range();
+                Ok(lexer::Token::String(buffer.buffer[start..end].to_string()))
+            }
+            ("word", []) => {
+                let word_chars = self.setting_string("WORDCHARS", "_");
+                let buffer = self.buffer.lock().unwrap();
+                let (start, end) = buffer.word_range(&word_chars);
+                Ok(lexer::Token::String(buffer.buffer[start..end].to_string()))
+            }
+            ("line", []) => {
+                let buffer = self.buffer.lock().unwrap();
+                let (start, end) = (buffer.line_start(buffer.cursor), buffer.line_end(buffer.cursor));
+                Ok(lexer::Token::String(buffer.buffer[start..end].to_string()))
+            }
+            ("range", [lexer::Token::Integer(start), lexer::Token::Integer(end)]) => {
+                let buffer = self.buffer.lock().unwrap();
+                let (start, end) = (*start.max(&0) as usize, (*end.max(&0) as usize).min(buffer.buffer.len()));
+                match buffer.buffer.get(start..end.max(start)) {
+                    Some(text) => Ok(lexer::Token::String(text.to_string())),
+                    None => Err(format!("range({}, {}) is not on character boundaries", start, end)),
+                }
+            }
+            // 行番号は 1 始まりで、終わりの行も含む
+            ("getline", [lexer::Token::Integer(line)]) => self.call("getlines", vec![lexer::Token::Integer(*line), lexer::Token::Integer(*line)]),
+            ("getlines", [lexer::Token::Integer(from), lexer::Token::Integer(to)]) => {
+                let buffer = self.buffer.lock().unwrap();
+                let lines: Vec<&str> = buffer.buffer.split('\n').collect();
+                if *from < 1 || *to < *from || *to as usize > lines.len() {
+                    return Err(format!("line out of range: {}..{} (buffer has {} lines)", from, to, lines.len()));
+                }
+                Ok(lexer::Token::String(lines[*from as usize - 1..*to as usize].join("\n")))
+            }
+            ("buffer", []) => Ok(lexer::Token::String(self.buffer.lock().unwrap().buffer.clone())),
+            ("lines", []) => {
+                let buffer = self.buffer.lock().unwrap();
+                let text = buffer.buffer.strip_suffix('\n').unwrap_or(&buffer.buffer);
+                if text.is_empty() {
+                    return Ok(lexer::Token::List(Vec::new()));
+                }
+                Ok(lexer::Token::List(text.split('\n').map(|line| lexer::Token::String(line.to_string())).collect()))
+            }
+            ("cursor", []) => Ok(lexer::Token::Integer(self.buffer.lock().unwrap().cursor as i64)),
+            ("lineno", []) => {
+                let buffer = self.buffer.lock().unwrap();
+                Ok(lexer::Token::Integer(buffer.buffer[..buffer.cursor].matches('\n').count() as i64 + 1))
+            }
+            ("buflen", []) => Ok(lexer::Token::Integer(self.buffer.lock().unwrap().buffer.len() as i64)),
 
-                    let mut param1_ = 0;
-                    let mut param2_ = 0;
+            // コードを今のローカル変数を取り込んだクロージャーにする
+            // 数式の中の {...} はマップなので、コードは変数か code("...") で渡す (closure(code("a$p$")))
+            ("closure", [lexer::Token::Code(code)]) => Ok(self.closure(code)),
 
-                    if let lexer::Token::Integer(param1) = param1 {
-                        param1_ = *param1;
-                    }
+            // 変数があるか (ないときにエラーにせず調べる)
+            ("exists", [lexer::Token::String(name)]) => Ok(lexer::Token::Integer(self.lookup_var(name).is_some() as i64)),
 
-                    if let lexer::Token::Integer(param2) = param2 {
-                        param2_ = *param2;
-                    }
+            _ if ENV_FUNCTIONS.contains(&name) => Err(format!("invalid arguments for {}: {:?}", name, args)),
 
-                    if let lexer::Token::Var(param1) = param1 {
-                        if let Some(param1) = self.vars.lock().unwrap().get(param1) {
-                            if let lexer::Token::Integer(param1) = param1 {
-                                param1_ = *param1;
-                            }
-                        }
-                    }
+            // ^DF^ で定義した関数
+            _ => match self.lookup_callable(name) {
+                Some(func @ lexer::Token::Func { .. }) => self.call_function(name, &func, args),
+                _ => Err(format!("unknown function: {}", name)),
+            },
+        }
+    }
+}
 
-                    if let lexer::Token::Var(param2) = param2 {
-                        if let Some(param2) = self.vars.lock().unwrap().get(param2) {
-                            if let lexer::Token::Integer(param2) = param2 {
-                                param2_ = *param2;
-                            } 
-                        }
-                    }
+// handle_multicommand で処理する組み込みのコマンド (^XX^) の名前
+const BUILTIN_COMMANDS: &[&str] = &[
+    "LI", "Lo", "WH", "FO", "FE", "IF", "EI", "SW", "BR", "CN",
+    "PU", "PO", "PT", "DL",
+    "DF", "RT", "GL", "EV", "UN", "CS", "LV",
+    "WF", "WB", "EF", "EB", "SF", "SB", "PF", "PB", "DW", "DE", "DB",
+    "UC", "LC", "TC", "SC",
+    "UM", "SL", "SO", "UQ", "RV", "SH",
+    ">", "<", "ET", "UT", "ST", "CB", "FL",
+];
+
+// Env::call で処理する組み込み関数の名前
+const ENV_FUNCTIONS: &[&str] = &[
+    "count", "char", "word", "line", "range", "getline", "getlines", "buffer", "lines", "cursor", "lineno", "buflen", "exists", "closure",
+];
+
+// 組み込み関数の名前か (数式の関数と Env::call の関数)
+fn is_builtin_function(name: &str) -> bool {
+    expr::is_builtin(name) || ENV_FUNCTIONS.contains(&name)
+}
 
-                    if let lexer::Token::Expr(param1) = param1 {
-                        if let Some(lexer::Token::Integer(param1)) = self.process_expr(param1) {
-                            param1_ = param1
-                        } else {
-                            error("formula eval error");
-                        }
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_or_infix() {
+        let pleco = PLECo::new();
+        pleco.vars.lock().unwrap().insert(String::from("x"), lexer::Token::Integer(10));
+
+        // 中置記法として読めないものは前置記法 (x は掛け算)
+        assert_eq!(pleco.process_expr("x*3;*5;"), Some(lexer::Token::Integer(15)));
+        assert_eq!(pleco.process_expr("-$x$*4;"), Some(lexer::Token::Integer(6)));
+        assert_eq!(pleco.process_expr("=*2;*2;"), Some(lexer::Token::Integer(1)));
+        assert_eq!(pleco.process_expr("-$x$$x$"), Some(lexer::Token::Integer(0)));
+        // 中置記法 (x は変数)
+        assert_eq!(pleco.process_expr("x - 1"), Some(lexer::Token::Integer(9)));
+        assert_eq!(pleco.process_expr("x*2"), Some(lexer::Token::Integer(20)));
+        assert_eq!(pleco.process_expr("-x"), Some(lexer::Token::Integer(-10)));
+        assert_eq!(pleco.process_expr("-(1+2)*3"), Some(lexer::Token::Integer(-9)));
+        assert_eq!(pleco.process_expr("-$x$ * 3"), Some(lexer::Token::Integer(-30)));
+    }
 
-                    if let lexer::Token::Expr(param2) = param2 {
-                        if let Some(lexer::Token::Integer(param2)) = self.process_expr(param2) {
-                            param2_ = param2
-                        } else {
-                            error("formula eval error");
-                        }
-                    }
+    #[test]
+    fn parens_inside_strings() {
+        let pleco = PLECo::new();
 
-                    match operation {
-                        lexer::Token::Command('+') => return Some(lexer::Token::Integer(param1_ + param2_)),
-                        lexer::Token::Command('-') => return Some(lexer::Token::Integer(param1_ - param2_)),
-                        lexer::Token::Command('x') => return Some(lexer::Token::Integer(param1_ * param2_)),
-                        lexer::Token::Command('/') => return Some(lexer::Token::Integer(param1_ / param2_)),
-                        lexer::Token::Command('%') => return Some(lexer::Token::Integer(param1_ % param2_)),
-                        lexer::Token::Command('=') => return Some(lexer::Token::Integer( if param1_ == param2_ { 1 } else { 0 } )),
-                        lexer::Token::Command('>') => return Some(lexer::Token::Integer( if param1_ > param2_ { 1 } else { 0 } )),
-                        lexer::Token::Command('<') => return Some(lexer::Token::Integer( if param1_ < param2_ { 1 } else { 0 } )),
-                        lexer::Token::Command('!') => return Some(lexer::Token::Integer( if param1_ != param2_ { 1 } else { 0 } )),
-                        _ => { error("unknown operation"); }
-                    }
-                }
-            }
+        // 文字列の中の括弧で (...) が閉じたり深くなったりしない
+        pleco.execute(r#"@$s$(replace("a(b", "(", "["))@$t$(")" + "x")"#);
+        assert_eq!(pleco.lookup_var("s"), Some(lexer::Token::String(String::from("a[b"))));
+        assert_eq!(pleco.lookup_var("t"), Some(lexer::Token::String(String::from(")x"))));
+    }
 
+    #[test]
+    fn builtin_commands_are_dispatched() {
+        // BUILTIN_COMMANDS の名前はすべて handle_multicommand の match で処理する (unreachable! に落ちない)
+        for name in BUILTIN_COMMANDS {
+            let pleco = PLECo::new();
+            let commands = [lexer::Token::MultiLengthCommand(name.to_string())];
+            pleco.handle_multicommand(0, &commands, name, false);
         }
+    }
+
+    #[test]
+    fn code_in_function_stays_code() {
+        let pleco = PLECo::new();
 
-        None
+        // 関数の中で @ に入れたコードは、M で呼んでも関数のスコープで動く
+        pleco.execute(r#"^DF^$f$""{@$y$*0;@$c${@$y$*1;}M$c$^GL^$out$$y$^GL^$src$(str(c))}^f^"#);
+        assert_eq!(pleco.lookup_var("out"), Some(lexer::Token::Integer(1)));
+        assert_eq!(pleco.lookup_var("src"), Some(lexer::Token::String(String::from("@$y$*1;"))));
 
+        // ^RT^{...} は引数を取り込んだクロージャーを返す
+        pleco.execute(r#"^DF^$mk$"s"{^RT^{^GL^$got$$s$}}@$p$(mk("x"))M$p$"#);
+        assert_eq!(pleco.lookup_var("got"), Some(lexer::Token::String(String::from("x"))));
+        assert_eq!(pleco.lookup_var("s"), None);
+
+        // 数式の中では code("...") でコードを作って closure() に渡す
+        pleco.execute(r#"^DF^$mk2$"s"{^RT^(closure(code("^GL^$got$$s$")))}@$q$(mk2("y"))M$q$"#);
+        assert_eq!(pleco.lookup_var("got"), Some(lexer::Token::String(String::from("y"))));
+    }
+
+    #[test]
+    fn loops_and_labels() {
+        let pleco = PLECo::new();
+
+        // ラベル付きの break / continue は外側のループまで抜ける
+        pleco.execute(r#"@$n$*0;^FO:outer^$i$*0;*3;*1;{^FO^$j$*0;*3;*1;{^IF^(j == 1){^CN:outer^}{}@$n$(n + 1)}}"#);
+        assert_eq!(pleco.lookup_var("n"), Some(lexer::Token::Integer(3)));
+        pleco.execute(r#"@$n$*0;^LI:outer^{^LI^{@$n$(n + 1)^BR:outer^}@$n$*100;}"#);
+        assert_eq!(pleco.lookup_var("n"), Some(lexer::Token::Integer(1)));
+
+        // マクロの中の break は呼び出し元のループを抜ける
+        pleco.execute(r#"@$stop${^IF^(n >= 5){^BR^}{}}@$n$*0;^LI^{@$n$(n + 1)M$stop$}"#);
+        assert_eq!(pleco.lookup_var("n"), Some(lexer::Token::Integer(5)));
+
+        pleco.execute(r#"@$n$*1;^WH^(n < 100){@$n$(n * 2)}"#);
+        assert_eq!(pleco.lookup_var("n"), Some(lexer::Token::Integer(128)));
+
+        // 増分が負なら数を減らしながら回り、終了の値は含まない
+        pleco.execute(r#"@$s$""^FO^$i$*5;*0;*-2;{@$s$(s + str(i))}"#);
+        assert_eq!(pleco.lookup_var("s"), Some(lexer::Token::String(String::from("531"))));
+
+        // ループの外の break は flow に残る (execute はここでエラーにする)
+        pleco.handle_command("^BR:outer^");
+        assert_eq!(*pleco.flow.lock().unwrap(), Flow::Break(Some(String::from("outer"))));
+        *pleco.flow.lock().unwrap() = Flow::Normal;
+    }
+
+    #[test]
+    fn branches() {
+        let pleco = PLECo::new();
+
+        pleco.execute(r#"@$x$*2;^EI^(x == 1){@$r$"one"}(x == 2){@$r$"two"}{@$r$"other"}"#);
+        assert_eq!(pleco.lookup_var("r"), Some(lexer::Token::String(String::from("two"))));
+        pleco.execute(r#"@$x$*9;^EI^(x == 1){@$r$"one"}{@$r$"other"}"#);
+        assert_eq!(pleco.lookup_var("r"), Some(lexer::Token::String(String::from("other"))));
+        // それ以外を省略して、どれにも当たらなければ何もしない
+        pleco.execute(r#"@$r$"none"^EI^(x == 1){@$r$"one"}"#);
+        assert_eq!(pleco.lookup_var("r"), Some(lexer::Token::String(String::from("none"))));
+
+        pleco.execute(r#"@$k$"b"^SW^$k$"a"{@$r$*1;}"b"{@$r$*2;}{@$r$*3;}"#);
+        assert_eq!(pleco.lookup_var("r"), Some(lexer::Token::Integer(2)));
+        pleco.execute(r#"^SW^(1 + 1)*1;{@$r$*1;}{@$r$*3;}"#);
+        assert_eq!(pleco.lookup_var("r"), Some(lexer::Token::Integer(3)));
+        pleco.execute(r#"@$r$*0;^SW^*7;*1;{@$r$*1;}"#);
+        assert_eq!(pleco.lookup_var("r"), Some(lexer::Token::Integer(0)));
+
+        // = は両辺を評価してから比べる
+        pleco.execute(r#"@$a$*1;=$a$(2 - 1){@$r$"eq"}{@$r$"ne"}"#);
+        assert_eq!(pleco.lookup_var("r"), Some(lexer::Token::String(String::from("eq"))));
+        pleco.execute(r#"=$a$"1"{@$r$"eq"}{@$r$"ne"}"#);
+        assert_eq!(pleco.lookup_var("r"), Some(lexer::Token::String(String::from("ne"))));
     }
 
+    #[test]
+    fn tail_call_ignores_trailing_comment() {
+        let pleco = PLECo::new();
+
+        // 末尾の呼び出しはスタックを伸ばさないので MAXDEPTH を超えて繰り返せる
+        pleco.execute("@$MAXDEPTH$*50;@$n$*0;@$g${@$n$(n + 1)^IF^(n < 200){g}{} #done#}g");
+        assert_eq!(pleco.lookup_var("n"), Some(lexer::Token::Integer(200)));
+    }
 }